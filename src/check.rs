@@ -0,0 +1,546 @@
+use crate::config::CheckConfig;
+use crate::keys::{
+    CertEntry, CertKey, FloodAction, KeyLayout, KeyOptions, cert_rejection, has_conflict,
+    key_files, mail_to_key_entry, parse_key_file, read_keys,
+};
+use crate::policy::get_policy;
+use anyhow::{Result, bail};
+use sequoia_openpgp::Cert;
+use sequoia_openpgp::cert::amalgamation::{ValidAmalgamation, ValidateAmalgamation};
+use sequoia_openpgp::types::RevocationStatus;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Keywords defined for the policy flags file.
+const POLICY_KEYWORDS: &[&str] = &[
+    "mailbox-only",
+    "dane-only",
+    "auth-submit",
+    "protocol-version",
+    "submission-address",
+];
+
+/// Counts the problems while they are printed.
+#[derive(Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+    /// All printed lines, so the report can be inspected in tests.
+    lines: Vec<String>,
+}
+
+impl Report {
+    fn line(&mut self, indent: usize, message: impl Display) {
+        let line = format!("{:indent$}{message}", "");
+        println!("{line}");
+        self.lines.push(line);
+    }
+
+    fn error(&mut self, indent: usize, message: impl Display) {
+        self.errors += 1;
+        self.line(indent, format!("error: {message}"));
+    }
+
+    fn warning(&mut self, indent: usize, message: impl Display) {
+        self.warnings += 1;
+        self.line(indent, format!("warning: {message}"));
+    }
+}
+
+/// Reads all key files exactly like the server does and prints, for every file, which addresses are served
+/// and why certificates or user IDs are not. Afterwards, conflicts and the policy directory are checked.
+///
+/// Fails if any errors were found, e.g. unreadable files, rejected certificates or secret key material.
+/// Warnings, e.g. for keys expiring soon or conflicting addresses, do not fail the check.
+pub fn check(config: &CheckConfig) -> Result<()> {
+    // file paths are compared against the preferred directory, like in the server
+    let keys_path = Path::new(&config.keys.keys_path).canonicalize()?;
+    let options = config.keys.key_options()?;
+
+    let report = check_paths(
+        &keys_path,
+        &options,
+        config.keys.policy.as_deref(),
+        config.expiry_warning,
+    )?;
+    if report.errors > 0 {
+        bail!("Found {} errors", report.errors);
+    }
+
+    Ok(())
+}
+
+fn check_paths(
+    keys_path: &Path,
+    options: &KeyOptions,
+    policy_dir: Option<&str>,
+    expiry_warning: Duration,
+) -> Result<Report> {
+    let mut report = Report::default();
+    let mut addresses: HashMap<CertKey, Vec<CertEntry>> = HashMap::new();
+
+    let files = key_files(keys_path, options)?;
+    let mut certs = 0;
+    for file in &files {
+        report.line(0, file.to_string_lossy());
+        certs += check_file(
+            keys_path,
+            file,
+            options,
+            expiry_warning,
+            &mut report,
+            &mut addresses,
+        );
+    }
+
+    check_conflicts(&addresses, options, &mut report);
+
+    if let Some(policy_dir) = policy_dir {
+        let domains = addresses
+            .keys()
+            .map(|key| key.domain.clone())
+            .collect::<BTreeSet<_>>();
+        check_policies(policy_dir, &domains, &mut report)?;
+    }
+
+    println!(
+        "Checked {} files with {certs} keys for {} addresses: {} errors, {} warnings",
+        files.len(),
+        addresses.len(),
+        report.errors,
+        report.warnings
+    );
+
+    Ok(report)
+}
+
+/// Checks a single key file, adds the entries read from it to `addresses` and returns the number of keys in it.
+fn check_file(
+    keys_path: &Path,
+    path: &Path,
+    options: &KeyOptions,
+    expiry_warning: Duration,
+    report: &mut Report,
+    addresses: &mut HashMap<CertKey, Vec<CertEntry>>,
+) -> usize {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) => {
+            report.error(2, format!("unreadable file: {e}"));
+            return 0;
+        }
+    };
+
+    let (parsed, secret_keys) = parse_key_file(&content);
+    if secret_keys {
+        if options.reject_secret_keys {
+            report.error(2, "contains secret key material, the file is refused");
+            return 0;
+        }
+        report.error(
+            2,
+            "contains secret key material, remove it from the keys path",
+        );
+    }
+
+    if parsed.is_empty() && !secret_keys {
+        report.error(2, "no keys found");
+    }
+    let mut certs = Vec::new();
+    for cert in parsed {
+        match cert {
+            Ok(cert) => certs.push(cert),
+            Err(e) => report.error(2, format!("unreadable key: {e:#}")),
+        }
+    }
+
+    // the entries the server would index
+    let entries = match read_keys(keys_path, path, options) {
+        Ok(file) => file.entries,
+        Err(e) => {
+            report.error(2, format!("{e:#}"));
+            Vec::new()
+        }
+    };
+
+    for cert in &certs {
+        check_cert(cert, &entries, options, expiry_warning, report);
+    }
+
+    for (key, entry) in entries {
+        addresses.entry(key).or_default().push(entry);
+    }
+
+    certs.len()
+}
+
+fn check_cert(
+    cert: &Cert,
+    entries: &[(CertKey, CertEntry)],
+    options: &KeyOptions,
+    expiry_warning: Duration,
+    report: &mut Report,
+) {
+    report.line(2, format!("key {}", cert.fingerprint()));
+    let validation = &options.validation;
+
+    let valid = match cert.with_policy(validation.policy(), validation.time()) {
+        Ok(valid) => valid,
+        Err(e) => {
+            report.error(4, format!("not served, rejected by policy: {e:#}"));
+            return;
+        }
+    };
+    if let Some(reason) = cert_rejection(&valid) {
+        report.error(4, format!("not served: {reason}"));
+        return;
+    }
+
+    if let Some(expiry) = valid.primary_key().key_expiration_time() {
+        let now = validation.time().unwrap_or_else(SystemTime::now);
+        let message = format!("expires at {}", humantime::format_rfc3339_seconds(expiry));
+        if expiry.duration_since(now).unwrap_or_default() < expiry_warning {
+            report.warning(4, message);
+        } else {
+            report.line(4, message);
+        }
+    }
+
+    let violations = options.limits.violations(cert);
+    if !violations.is_empty() {
+        let violations = violations.join(", ");
+        match options.limits.action {
+            FloodAction::Trim => {
+                report.warning(4, format!("exceeds limits: {violations}, it is trimmed"))
+            }
+            FloodAction::Reject => {
+                report.error(4, format!("not served, exceeds limits: {violations}"));
+                return;
+            }
+        }
+    }
+
+    for subkey in cert.keys().subkeys() {
+        if let Err(e) = subkey.with_policy(validation.policy(), validation.time()) {
+            report.warning(
+                4,
+                format!("subkey {} rejected: {e:#}", subkey.key().fingerprint()),
+            );
+        }
+    }
+
+    for userid in cert.userids() {
+        let Some(email) = userid.userid().email().ok().flatten() else {
+            report.line(
+                4,
+                format!("{}: not served, no email address", userid.userid()),
+            );
+            continue;
+        };
+        let (username, key) = match mail_to_key_entry(email) {
+            Ok(Some(address)) => address,
+            Ok(None) => {
+                report.warning(
+                    4,
+                    format!(
+                        "user ID {} not served: invalid email address",
+                        userid.userid()
+                    ),
+                );
+                continue;
+            }
+            Err(e) => {
+                report.warning(4, format!("user ID {} not served: {e:#}", userid.userid()));
+                continue;
+            }
+        };
+
+        let served = entries.iter().any(|(other, entry)| {
+            other == &key
+                && entry.username == username
+                && entry.cert.fingerprint() == cert.fingerprint()
+        });
+        if served {
+            report.line(4, format!("{}: {}", userid.userid(), key.hashed_username));
+            continue;
+        }
+        if !options.domains.allows(&key.domain) {
+            report.line(
+                4,
+                format!(
+                    "{}: not served, domain {} is not served",
+                    userid.userid(),
+                    key.domain
+                ),
+            );
+            continue;
+        }
+
+        match userid.with_policy(validation.policy(), validation.time()) {
+            Err(e) => report.warning(4, format!("user ID {} not served: {e:#}", userid.userid())),
+            Ok(userid) if matches!(userid.revocation_status(), RevocationStatus::Revoked(_)) => {
+                report.warning(
+                    4,
+                    format!("user ID {} not served: user ID revoked", userid.userid()),
+                )
+            }
+            // in the wks layout, a file only serves the address it is stored for
+            Ok(userid) if options.layout == KeyLayout::Wks => report.line(
+                4,
+                format!(
+                    "{}: not served from this file, it belongs to a different address",
+                    userid.userid()
+                ),
+            ),
+            Ok(userid) => report.warning(4, format!("user ID {} not served", userid.userid())),
+        }
+    }
+}
+
+fn check_conflicts(
+    addresses: &HashMap<CertKey, Vec<CertEntry>>,
+    options: &KeyOptions,
+    report: &mut Report,
+) {
+    let mut conflicts = addresses
+        .iter()
+        .filter(|(_, entries)| has_conflict(entries))
+        .collect::<Vec<_>>();
+    conflicts.sort_by(|(a, _), (b, _)| {
+        (&a.domain, &a.hashed_username).cmp(&(&b.domain, &b.hashed_username))
+    });
+
+    for (key, entries) in conflicts {
+        let claims = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{} ({})",
+                    entry.cert.fingerprint(),
+                    entry.path.to_string_lossy()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let served = options.conflicts.resolve(entries.clone()).len();
+        report.warning(
+            0,
+            format!(
+                "conflict: {}@{} ({}) is claimed by {claims}, serving {served} of {} entries",
+                entries[0].username,
+                key.domain,
+                key.hashed_username,
+                entries.len()
+            ),
+        );
+    }
+}
+
+/// Checks the syntax of the policy files and submission addresses, and that a policy is served for every domain.
+fn check_policies(policy_dir: &str, domains: &BTreeSet<String>, report: &mut Report) -> Result<()> {
+    let mut paths = fs::read_dir(policy_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            if path
+                .file_name()
+                .is_some_and(|name| name == "submission-address")
+            {
+                check_submission_addresses(&path, report)?;
+            }
+            continue;
+        }
+
+        report.line(0, path.to_string_lossy());
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                report.error(2, format!("unreadable policy: {e}"));
+                continue;
+            }
+        };
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, value) = match line.split_once(':') {
+                Some((keyword, value)) => (keyword.trim(), Some(value.trim())),
+                None => (line, None),
+            };
+            if !POLICY_KEYWORDS.contains(&keyword) {
+                report.warning(
+                    2,
+                    format!("line {}: unknown keyword '{keyword}'", number + 1),
+                );
+            } else if keyword == "protocol-version"
+                && value.and_then(|value| value.parse::<u32>().ok()).is_none()
+            {
+                report.error(
+                    2,
+                    format!("line {}: protocol-version must be a number", number + 1),
+                );
+            }
+        }
+    }
+
+    for domain in domains {
+        if get_policy(policy_dir, domain)?.is_none() {
+            report.warning(
+                0,
+                format!(
+                    "no policy for domain {domain} and no default policy, an empty policy is served"
+                ),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn check_submission_addresses(dir: &Path, report: &mut Report) -> Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    for path in paths {
+        report.line(0, path.to_string_lossy());
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                let address = content.trim();
+                if !address.contains('@') || address.contains(char::is_whitespace) {
+                    report.error(2, format!("'{address}' is not an email address"));
+                }
+            }
+            Err(e) => report.error(2, format!("unreadable submission address: {e}")),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::check::{Report, check_paths};
+    use crate::domain::Domains;
+    use crate::keys::{KeyLayout, KeyOptions, mail_to_key_entry};
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::serialize::SerializeInto;
+    use sequoia_openpgp::{Cert, Packet};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn write(path: &Path, cert: &Cert) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, cert.armored().to_vec().unwrap()).unwrap();
+    }
+
+    fn contains(report: &Report, line: &str) -> bool {
+        report.lines.iter().any(|other| other.trim() == line)
+    }
+
+    #[test]
+    fn user_ids() {
+        let dir = TempDir::new().unwrap();
+        let (expired, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .set_creation_time(SystemTime::now() - 2 * DAY)
+            .set_validity_period(DAY)
+            .generate()
+            .unwrap();
+        let (revoked, revocation) = CertBuilder::general_purpose(Some("bob@example.com"))
+            .generate()
+            .unwrap();
+        let (revoked, _) = revoked.insert_packets(Packet::from(revocation)).unwrap();
+        let (domains, _) = CertBuilder::general_purpose(Some("carol@example.com"))
+            .add_userid("carol@example.org")
+            .add_userid("carol@-example.com")
+            .generate()
+            .unwrap();
+        write(&dir.path().join("expired.asc"), &expired);
+        write(&dir.path().join("revoked.asc"), &revoked);
+        write(&dir.path().join("domains.asc"), &domains);
+
+        let options = KeyOptions {
+            domains: Domains::new(&["example.com".to_string()]),
+            ..KeyOptions::default()
+        };
+        let report = check_paths(dir.path(), &options, None, Duration::ZERO).unwrap();
+
+        assert!(report.lines.iter().any(|line| {
+            line.starts_with("    error: not served: The primary key is not live: Expired")
+        }));
+        assert!(contains(&report, "error: not served: certificate revoked"));
+        let (_, carol) = mail_to_key_entry("carol@example.com").unwrap().unwrap();
+        assert!(contains(
+            &report,
+            &format!("carol@example.com: {}", carol.hashed_username)
+        ));
+        assert!(contains(
+            &report,
+            "carol@example.org: not served, domain example.org is not served"
+        ));
+        // the reason an address cannot be normalized is reported
+        assert!(contains(
+            &report,
+            "warning: user ID carol@-example.com not served: '-example.com' is not a valid domain name"
+        ));
+        assert_eq!(report.errors, 2);
+        assert_eq!(report.warnings, 1);
+    }
+
+    #[test]
+    fn wks() {
+        let dir = TempDir::new().unwrap();
+        let (alice, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let (_, alice_key) = mail_to_key_entry("alice@example.com").unwrap().unwrap();
+        let (_, bob_key) = mail_to_key_entry("bob@example.com").unwrap().unwrap();
+        write(
+            &dir.path()
+                .join("example.com/hu")
+                .join(&alice_key.hashed_username),
+            &alice,
+        );
+        // alice's key stored at bob's path
+        write(
+            &dir.path()
+                .join("example.com/hu")
+                .join(&bob_key.hashed_username),
+            &alice,
+        );
+
+        let options = KeyOptions {
+            layout: KeyLayout::Wks,
+            ..KeyOptions::default()
+        };
+        let report = check_paths(dir.path(), &options, None, Duration::ZERO).unwrap();
+
+        assert!(contains(
+            &report,
+            &format!("alice@example.com: {}", alice_key.hashed_username)
+        ));
+        assert!(contains(
+            &report,
+            &format!(
+                "error: no user ID matches hash {} in domain example.com",
+                bob_key.hashed_username
+            )
+        ));
+        assert!(contains(
+            &report,
+            "alice@example.com: not served from this file, it belongs to a different address"
+        ));
+        assert_eq!(report.errors, 1);
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Result, bail};
+use idna::AsciiDenyList;
+use std::collections::BTreeSet;
+
+/// Maximum length of a domain name, without the trailing dot.
+const MAX_DOMAIN_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Normalizes a domain name, so that all spellings of a domain are looked up alike: it is lowercased, a trailing dot
+/// is removed and internationalized domain names are converted to their ASCII form (A-labels), e.g. `Bücher.example.`
+/// becomes `xn--bcher-kva.example`. Fails if the result is not a valid host name.
+pub fn normalize_domain(domain: &str) -> Result<String> {
+    let trimmed = domain.strip_suffix('.').unwrap_or(domain);
+    let Ok(ascii) = idna::domain_to_ascii_cow(trimmed.as_bytes(), AsciiDenyList::STD3) else {
+        bail!("'{domain}' is not a valid domain name");
+    };
+
+    if ascii.is_empty() || ascii.len() > MAX_DOMAIN_LENGTH {
+        bail!("'{domain}' is not a valid domain name");
+    }
+    for label in ascii.split('.') {
+        if label.is_empty()
+            || label.len() > MAX_LABEL_LENGTH
+            || label.starts_with('-')
+            || label.ends_with('-')
+        {
+            bail!("'{domain}' is not a valid domain name");
+        }
+    }
+
+    Ok(ascii.into_owned())
+}
+
+/// Whether `domain` can be used as a file name without escaping the directory it is joined to. Domains from
+/// requests and user IDs are used to name files in the policy directory and in exported trees.
+pub fn is_safe_file_name(domain: &str) -> bool {
+    !domain.is_empty() && !domain.starts_with('.') && !domain.contains(['/', '\\'])
+}
+
+/// The domains the server is authoritative for. User IDs of other domains are not indexed,
+/// and requests for them are answered with 404 without looking up any keys.
+#[derive(Clone, Debug, Default)]
+pub struct Domains {
+    /// Empty if all domains are served.
+    allowed: BTreeSet<String>,
+}
+
+impl Domains {
+    pub fn new(domains: &[String]) -> Self {
+        Self {
+            allowed: domains.iter().cloned().collect(),
+        }
+    }
+
+    /// Whether keys and policies are served for `domain`, which must be normalized, see [`normalize_domain`].
+    pub fn allows(&self, domain: &str) -> bool {
+        self.allowed.is_empty() || self.allowed.contains(domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{Domains, is_safe_file_name, normalize_domain};
+
+    #[test]
+    fn allows() {
+        assert!(Domains::default().allows("gmail.com"));
+
+        let domains = Domains::new(&["example.com".to_string(), "example.org".to_string()]);
+        assert!(domains.allows("example.com"));
+        assert!(domains.allows("example.org"));
+        assert!(!domains.allows("gmail.com"));
+        assert!(!domains.allows("sub.example.com"));
+    }
+
+    #[test]
+    fn safe_file_name() {
+        assert!(is_safe_file_name("example.com"));
+        for unsafe_name in ["", ".", "..", ".hidden", "../etc", "a/b", "a\\b"] {
+            assert!(!is_safe_file_name(unsafe_name), "{unsafe_name}");
+        }
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_domain("Example.COM.").unwrap(), "example.com");
+        assert_eq!(
+            normalize_domain("Bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            normalize_domain("xn--bcher-kva.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+
+        for invalid in [
+            "",
+            ".",
+            "example..com",
+            "exa mple.com",
+            "example.com/..",
+            "-example.com",
+            "under_score.com",
+            &format!("{}.com", "a".repeat(64)),
+        ] {
+            assert!(normalize_domain(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::ExportConfig;
+use crate::domain::is_safe_file_name;
+use crate::keys::{KeyDb, KeyStore};
+use crate::policy::{get_submission_address, policy_for_domain};
+use anyhow::{Context, Result};
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+use tracing::{debug, warn};
+
+/// Writes a static WKD tree into the output directory.
+///
+/// For every domain, `<output>/<domain>` contains both the direct (`.well-known/openpgpkey/hu/<hash>`)
+/// and the advanced (`.well-known/openpgpkey/<domain>/hu/<hash>`) method, so it can be used as web root
+/// for `<domain>` as well as `openpgpkey.<domain>`. Keys are read and serialized exactly like the server does.
+pub async fn export(config: &ExportConfig) -> Result<()> {
+    let store = KeyDb::new(
+        Path::new(&config.keys.keys_path),
+        config.keys.key_options()?,
+    )
+    .await?;
+    let mut keys = HashSet::new();
+    for stored in store.list()? {
+        debug!(
+            "Exporting key {} for '{}@{}' from {}",
+            stored.fingerprint, stored.username, stored.key.domain, stored.source
+        );
+        keys.insert(stored.key);
+    }
+
+    let output = Path::new(&config.output);
+    let mut domains = BTreeSet::new();
+    let mut addresses = 0;
+
+    for key in &keys {
+        // the domain is taken from user IDs, it must not be able to escape the output directory
+        if !is_safe_file_name(&key.domain) {
+            warn!("Skipping invalid domain '{}'", key.domain);
+            continue;
+        }
+        let Some(content) = store.get(key, None)? else {
+            continue;
+        };
+
+        let root = output.join(&key.domain).join(".well-known/openpgpkey");
+        for dir in [root.join("hu"), root.join(&key.domain).join("hu")] {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Creating {}", dir.to_string_lossy()))?;
+            fs::write(dir.join(&key.hashed_username), &content)?;
+        }
+
+        domains.insert(key.domain.clone());
+        addresses += 1;
+    }
+
+    for domain in &domains {
+        let policy = policy_for_domain(config.keys.policy.as_deref(), domain)?;
+        let submission_address = match &config.keys.policy {
+            Some(policy_dir) => get_submission_address(policy_dir, domain)?,
+            None => None,
+        };
+
+        let root = output.join(domain).join(".well-known/openpgpkey");
+        for dir in [root.clone(), root.join(domain)] {
+            fs::write(dir.join("policy"), &policy)?;
+            if let Some(submission_address) = &submission_address {
+                fs::write(dir.join("submission-address"), submission_address)?;
+            }
+        }
+    }
+
+    println!(
+        "Exported {addresses} addresses for {} domains to {}",
+        domains.len(),
+        output.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ExportConfig;
+    use crate::export::export;
+    use crate::keys::{mail_to_key_entry, parse_cert};
+    use clap::Parser;
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::serialize::SerializeInto;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        export: ExportConfig,
+    }
+
+    #[tokio::test]
+    async fn layout() {
+        let dir = TempDir::new().unwrap();
+        let (keys, policy, output) = (
+            dir.path().join("keys"),
+            dir.path().join("policy"),
+            dir.path().join("out"),
+        );
+        fs::create_dir_all(&keys).unwrap();
+        fs::create_dir_all(policy.join("submission-address")).unwrap();
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        fs::write(keys.join("alice.asc"), cert.armored().to_vec().unwrap()).unwrap();
+        fs::write(policy.join("default"), "protocol-version: 5\n").unwrap();
+        fs::write(
+            policy.join("submission-address").join("example.com"),
+            "key-submission@example.com\n",
+        )
+        .unwrap();
+
+        let args = Args::parse_from([
+            "wkd-server".as_ref(),
+            keys.as_os_str(),
+            output.as_os_str(),
+            "--policy".as_ref(),
+            policy.as_os_str(),
+        ]);
+        export(&args.export).await.unwrap();
+
+        let (_, key) = mail_to_key_entry("alice@example.com").unwrap().unwrap();
+        let root = output.join("example.com/.well-known/openpgpkey");
+        // the direct and the advanced method
+        for dir in [root.clone(), root.join("example.com")] {
+            let exported = fs::read(dir.join("hu").join(&key.hashed_username)).unwrap();
+            assert_eq!(
+                parse_cert(&exported).unwrap().fingerprint(),
+                cert.fingerprint()
+            );
+            assert_eq!(
+                fs::read_to_string(dir.join("policy")).unwrap(),
+                "protocol-version: 5\n"
+            );
+            assert_eq!(
+                fs::read_to_string(dir.join("submission-address")).unwrap(),
+                "key-submission@example.com\n"
+            );
+        }
+        assert_eq!(
+            fs::read_dir(&output)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>(),
+            ["example.com"]
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use crate::http::ApiContext;
+use crate::http::errors::ApiError;
+use crate::http::submission::{check_conflicts, wait_for_store};
+use crate::keys::parse_cert;
+use axum::body::Bytes;
+use axum::extract::{Path, Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use axum_extra::headers::authorization::Bearer;
+use axum_extra::headers::{Authorization, HeaderMapExt};
+use sequoia_openpgp::{Cert, Fingerprint};
+use serde::Serialize;
+use tracing::{info, warn};
+
+/// A certificate served for an address, as listed by the admin API.
+#[derive(Serialize)]
+struct KeyInfo {
+    address: String,
+    /// The WKD hash of the local part.
+    hash: String,
+    fingerprint: String,
+    source: String,
+    /// RFC 3339 timestamp, `null` if the certificate does not expire.
+    expiration: Option<String>,
+}
+
+/// Compares in constant time, so that the token cannot be guessed from response times.
+fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn require_token(
+    State(state): State<ApiContext>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    // the admin listener is only started with a token
+    let expected = state.config.admin_token.as_deref().unwrap_or_default();
+    match headers.typed_get::<Authorization<Bearer>>() {
+        Some(auth) if tokens_match(auth.token().as_bytes(), expected.as_bytes()) => {
+            Ok(next.run(request).await)
+        }
+        _ => {
+            warn!("Rejecting unauthorized admin request to {}", request.uri());
+            Err(ApiError::Unauthorized)
+        }
+    }
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Result<Fingerprint, ApiError> {
+    Fingerprint::from_hex(fingerprint)
+        .map_err(|_| ApiError::BadRequest(format!("Invalid fingerprint '{fingerprint}'.")))
+}
+
+/// Parses an uploaded certificate, which must not contain secret key material.
+fn parse_upload(body: &[u8]) -> Result<Cert, ApiError> {
+    let Some(cert) = parse_cert(body) else {
+        return Err(ApiError::BadRequest("Could not parse certificate.".into()));
+    };
+    if cert.is_tsk() {
+        return Err(ApiError::BadRequest(
+            "Certificate contains secret key material.".into(),
+        ));
+    }
+    Ok(cert)
+}
+
+async fn list_keys(State(state): State<ApiContext>) -> Result<Json<Vec<KeyInfo>>, ApiError> {
+    let keys = state
+        .with_key_store(|store| store.list())
+        .await?
+        .into_iter()
+        .map(|key| KeyInfo {
+            address: format!("{}@{}", key.username, key.key.domain),
+            hash: key.key.hashed_username,
+            fingerprint: key.fingerprint.to_hex(),
+            source: key.source,
+            expiration: key
+                .expiration
+                .map(|time| humantime::format_rfc3339_seconds(time).to_string()),
+        })
+        .collect();
+
+    Ok(Json(keys))
+}
+
+async fn upload_key(
+    State(state): State<ApiContext>,
+    body: Bytes,
+) -> Result<(StatusCode, String), ApiError> {
+    let cert = parse_upload(&body)?;
+    let keys = check_conflicts(&state, &cert, None).await?;
+    if keys.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Certificate has no valid user ID with an email address.".into(),
+        ));
+    }
+
+    let events = state.key_store.subscribe();
+    state.key_store.publish(&cert)?;
+    info!(
+        "Published key {} through the admin API.",
+        cert.fingerprint()
+    );
+    wait_for_store(events, keys, &cert.fingerprint()).await;
+
+    Ok((
+        StatusCode::CREATED,
+        format!("Key {} published.\n", cert.fingerprint().to_hex()),
+    ))
+}
+
+/// Publishes the uploaded certificate in place of the certificate `fingerprint`, e.g. after a key rotation.
+async fn replace_key(
+    State(state): State<ApiContext>,
+    Path(fingerprint): Path<String>,
+    body: Bytes,
+) -> Result<String, ApiError> {
+    let old = parse_fingerprint(&fingerprint)?;
+    let cert = parse_upload(&body)?;
+    let stored = {
+        let old = old.clone();
+        state
+            .with_key_store(move |store| Ok(store.list()?.iter().any(|key| key.fingerprint == old)))
+            .await?
+    };
+    if !stored {
+        return Err(ApiError::NotFound);
+    }
+    let mut keys = check_conflicts(&state, &cert, Some(&old)).await?;
+    if keys.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Certificate has no valid user ID with an email address.".into(),
+        ));
+    }
+
+    let events = state.key_store.subscribe();
+    // publishing first means both keys are served briefly, instead of none
+    state.key_store.publish(&cert)?;
+    if cert.fingerprint() != old {
+        keys.extend(state.key_store.remove(&old)?);
+    }
+    info!(
+        "Replaced key {} by {} through the admin API.",
+        old,
+        cert.fingerprint()
+    );
+    wait_for_store(events, keys, &cert.fingerprint()).await;
+
+    Ok(format!(
+        "Key {} replaced by {}.\n",
+        old.to_hex(),
+        cert.fingerprint().to_hex()
+    ))
+}
+
+async fn delete_key(
+    State(state): State<ApiContext>,
+    Path(fingerprint): Path<String>,
+) -> Result<String, ApiError> {
+    let fingerprint = parse_fingerprint(&fingerprint)?;
+
+    let events = state.key_store.subscribe();
+    let keys = state.key_store.remove(&fingerprint)?;
+    if keys.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+    info!("Deleted key {} through the admin API.", fingerprint);
+    wait_for_store(events, keys, &fingerprint).await;
+
+    Ok(format!("Key {} deleted.\n", fingerprint.to_hex()))
+}
+
+pub fn router(state: ApiContext) -> Router<ApiContext> {
+    Router::new()
+        .route("/keys", get(list_keys).post(upload_key))
+        .route("/keys/{fingerprint}", put(replace_key).delete(delete_key))
+        .route_layer(middleware::from_fn_with_state(state, require_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::admin::tokens_match;
+
+    #[test]
+    fn token() {
+        assert!(tokens_match(b"secret", b"secret"));
+        assert!(!tokens_match(b"secreT", b"secret"));
+        assert!(!tokens_match(b"secret2", b"secret"));
+        assert!(!tokens_match(b"", b"secret"));
+    }
+}
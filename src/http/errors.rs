@@ -1,11 +1,16 @@
 use axum::http::StatusCode;
+use axum::http::header::WWW_AUTHENTICATE;
 use axum::response::{IntoResponse, Response};
 use std::error::Error;
 
 pub enum ApiError {
     NotFound,
+    Unauthorized,
     BadRequest(String),
+    Conflict(String),
+    TooManyRequests(String),
     Internal(String),
+    Unavailable(String),
 }
 
 impl From<std::io::Error> for ApiError {
@@ -30,8 +35,17 @@ impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         match self {
             ApiError::NotFound => (StatusCode::NOT_FOUND, "Not Found.").into_response(),
+            ApiError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, "Bearer")],
+                "Unauthorized.",
+            )
+                .into_response(),
             ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            ApiError::Conflict(msg) => (StatusCode::CONFLICT, msg).into_response(),
+            ApiError::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg).into_response(),
             ApiError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response(),
+            ApiError::Unavailable(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg).into_response(),
         }
     }
 }
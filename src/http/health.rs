@@ -0,0 +1,39 @@
+use crate::http::ApiContext;
+use crate::http::errors::ApiError;
+use anyhow::{Context, Result};
+use axum::Router;
+use axum::extract::State;
+use axum::routing::get;
+use std::fs;
+use tracing::warn;
+
+/// The process is alive and answers requests.
+async fn get_healthz() -> &'static str {
+    "OK"
+}
+
+/// The server only starts listening after the keys were read initially, so it is ready as long as
+/// changes to the keys are still picked up and the policies can be read.
+async fn get_readyz(State(state): State<ApiContext>) -> Result<&'static str, ApiError> {
+    match readiness(&state) {
+        Ok(()) => Ok("Ready"),
+        Err(e) => {
+            warn!("Not ready: {:#}", e);
+            Err(ApiError::Unavailable(format!("Not ready: {e:#}")))
+        }
+    }
+}
+
+fn readiness(state: &ApiContext) -> Result<()> {
+    state.key_store.ready()?;
+    if let Some(policy_dir) = &state.config.keys.policy {
+        fs::read_dir(policy_dir).context("Reading policy directory")?;
+    }
+    Ok(())
+}
+
+pub fn router() -> Router<ApiContext> {
+    Router::new()
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz))
+}
@@ -1,25 +1,123 @@
 use crate::http::errors::ApiError;
-use axum::http::{HeaderMap, HeaderValue, uri::Authority};
+use crate::http::tls::TlsListener;
+use axum::extract::connect_info::Connected;
+use axum::http::{HeaderMap, uri::Authority};
+use axum::serve::IncomingStream;
 use axum_extra::headers::{HeaderMapExt, Host};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpListener;
+use tracing::debug;
 
+const FORWARDED: &str = "Forwarded";
 const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
 
-fn parse_forwarded_host(value: &HeaderValue) -> Result<Host, ()> {
-    let authority = Authority::try_from(value.as_bytes()).map_err(|_| ())?;
+/// The address of the client connected to the server, which may be a reverse proxy.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+fn parse_forwarded_host(value: &[u8]) -> Result<Host, ()> {
+    let authority = Authority::try_from(value).map_err(|_| ())?;
     Ok(Host::from(authority))
 }
 
-pub fn domain_from_headers(headers: &HeaderMap) -> Result<String, ApiError> {
-    match headers.get(X_FORWARDED_HOST).map(parse_forwarded_host) {
-        Some(Ok(forwarded_host)) => {
-            return Ok(forwarded_host.hostname().to_string());
+/// Splits `value` at `separator`, except inside quoted strings.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (index, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == separator && !quoted => {
+                parts.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
         }
-        Some(Err(_)) => {
-            return Err(ApiError::BadRequest(
-                "Invalid X-Forwarded-Host header.".into(),
-            ));
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// Returns the `host` parameter of the last element of the RFC 7239 `Forwarded` headers, which was added by the
+/// proxy connected to the server. Proxies may append their element to the last header line or add a line of their
+/// own, so the last element of the last line is used. Elements added by earlier proxies, or sent by the client,
+/// are ignored.
+fn forwarded_host(headers: &HeaderMap) -> Result<Option<Host>, ()> {
+    let Some(value) = headers.get_all(FORWARDED).iter().next_back() else {
+        return Ok(None);
+    };
+    let value = value.to_str().map_err(|_| ())?;
+    let Some(element) = split_unquoted(value, ',').pop() else {
+        return Ok(None);
+    };
+
+    for pair in split_unquoted(element, ';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("host") {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            return parse_forwarded_host(value.as_bytes()).map(Some);
         }
-        None => {}
+    }
+
+    Ok(None)
+}
+
+/// Returns the domain a request is for. The `Forwarded` and `X-Forwarded-Host` headers are only honored if the
+/// request comes from one of the `trusted_proxies`, otherwise anyone could pick the domain. If they are not set,
+/// the `Host` header is used.
+pub fn domain_from_headers(
+    headers: &HeaderMap,
+    peer: IpAddr,
+    trusted_proxies: &[IpNet],
+) -> Result<String, ApiError> {
+    // IPv4 clients of a dual-stack socket connect from IPv4-mapped IPv6 addresses
+    let peer = peer.to_canonical();
+    if trusted_proxies.iter().any(|net| net.contains(&peer)) {
+        match forwarded_host(headers) {
+            Ok(Some(forwarded_host)) => return Ok(forwarded_host.hostname().to_string()),
+            Err(_) => {
+                return Err(ApiError::BadRequest("Invalid Forwarded header.".into()));
+            }
+            Ok(None) => {}
+        }
+
+        match headers
+            .get(X_FORWARDED_HOST)
+            .map(|value| parse_forwarded_host(value.as_bytes()))
+        {
+            Some(Ok(forwarded_host)) => {
+                return Ok(forwarded_host.hostname().to_string());
+            }
+            Some(Err(_)) => {
+                return Err(ApiError::BadRequest(
+                    "Invalid X-Forwarded-Host header.".into(),
+                ));
+            }
+            None => {}
+        }
+    } else if headers.contains_key(FORWARDED) || headers.contains_key(X_FORWARDED_HOST) {
+        debug!("Ignoring forwarded headers from untrusted peer {}", peer);
     }
 
     match headers.typed_try_get::<Host>() {
@@ -29,3 +127,104 @@ pub fn domain_from_headers(headers: &HeaderMap) -> Result<String, ApiError> {
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::http::host::domain_from_headers;
+    use axum::http::{HeaderMap, HeaderValue};
+    use ipnet::IpNet;
+    use std::net::IpAddr;
+
+    fn domain(headers: &[(&'static str, &'static str)], peer: &str) -> Option<String> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        domain_from_headers(&map, peer.parse::<IpAddr>().unwrap(), &trusted).ok()
+    }
+
+    #[test]
+    fn forwarded() {
+        let host = ("host", "internal:8080");
+
+        assert_eq!(domain(&[host], "10.0.0.1").unwrap(), "internal");
+        assert_eq!(
+            domain(&[host, ("x-forwarded-host", "example.com")], "10.0.0.1").unwrap(),
+            "example.com"
+        );
+        // only the last element was added by the trusted proxy
+        assert_eq!(
+            domain(
+                &[
+                    host,
+                    (
+                        "forwarded",
+                        "host=evil.com, for=192.0.2.1;Host=\"example.org:443\""
+                    )
+                ],
+                "10.0.0.1"
+            )
+            .unwrap(),
+            "example.org"
+        );
+        assert_eq!(
+            domain(
+                &[host, ("forwarded", "host=evil.com, for=192.0.2.1")],
+                "10.0.0.1"
+            )
+            .unwrap(),
+            "internal"
+        );
+        // a proxy adding its own header line instead of appending to the client's one
+        assert_eq!(
+            domain(
+                &[
+                    host,
+                    ("forwarded", "host=evil.com"),
+                    ("forwarded", "for=192.0.2.1;host=example.org")
+                ],
+                "10.0.0.1"
+            )
+            .unwrap(),
+            "example.org"
+        );
+        // commas and semicolons in quoted strings do not separate elements
+        assert_eq!(
+            domain(
+                &[
+                    host,
+                    (
+                        "forwarded",
+                        "for=\"[2001:db8::1]\";host=example.org;proto=\"a,b;c\""
+                    )
+                ],
+                "10.0.0.1"
+            )
+            .unwrap(),
+            "example.org"
+        );
+        assert_eq!(
+            domain(
+                &[host, ("x-forwarded-host", "example.com")],
+                "::ffff:10.0.0.1"
+            )
+            .unwrap(),
+            "example.com"
+        );
+
+        // untrusted peers cannot pick the domain
+        assert_eq!(
+            domain(
+                &[
+                    host,
+                    ("x-forwarded-host", "example.com"),
+                    ("forwarded", "host=example.org")
+                ],
+                "192.0.2.1"
+            )
+            .unwrap(),
+            "internal"
+        );
+    }
+}
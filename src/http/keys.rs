@@ -1,8 +1,9 @@
 use crate::http::ApiContext;
 use crate::http::errors::ApiError;
-use crate::http::host::domain_from_headers;
+use crate::http::host::{PeerAddr, domain_from_headers};
+use crate::keys::CertKey;
 use axum::Router;
-use axum::extract::{Path, Query, State};
+use axum::extract::{ConnectInfo, Path, Query, State};
 use axum::http::HeaderMap;
 use axum::routing::get;
 use serde::Deserialize;
@@ -14,7 +15,17 @@ async fn get_key(
     domain: &str,
     username: Option<&String>,
 ) -> Result<Vec<u8>, ApiError> {
-    if let Some(key) = state.key_db.get(hash, domain, username).await? {
+    let domain = &state.served_domain(domain)?;
+    let cert_key = CertKey {
+        hashed_username: hash.to_string(),
+        domain: domain.to_string(),
+    };
+    let username = username.cloned();
+    let key = state
+        .with_key_store(move |store| store.get(&cert_key, username.as_deref()))
+        .await?;
+    state.metrics.record_lookup(key.is_some());
+    if let Some(key) = key {
         info!("Serving key for domain {domain}, hash {hash}.");
         Ok(key)
     } else {
@@ -25,10 +36,11 @@ async fn get_key(
 
 pub async fn get_key_direct(
     State(state): State<ApiContext>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
     Path(hash): Path<String>,
     headers: HeaderMap,
 ) -> Result<Vec<u8>, ApiError> {
-    let domain = domain_from_headers(&headers)?;
+    let domain = domain_from_headers(&headers, peer.ip(), &state.config.trusted_proxy)?;
     get_key(&state, &hash, &domain, None).await
 }
 
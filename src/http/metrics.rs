@@ -0,0 +1,246 @@
+use crate::http::ApiContext;
+use crate::http::errors::ApiError;
+use crate::keys::{StoreStats, StoredKey};
+use axum::Router;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// Counters recorded while serving requests. They are exported together with the state of the key store.
+#[derive(Default)]
+pub struct Metrics {
+    /// Requests by route and status code.
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_request(&self, route: &str, status: StatusCode) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), status.as_u16()))
+            .or_default() += 1;
+    }
+
+    /// Records whether a key lookup found a key.
+    pub fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn render(&self, keys: &[StoredKey], stats: &StoreStats) -> String {
+        let mut out = String::new();
+
+        let requests = self.requests.lock().unwrap().clone();
+        family(
+            &mut out,
+            "wkd_http_requests_total",
+            "counter",
+            "HTTP requests by route and status code.",
+            requests.into_iter().map(|((route, status), count)| {
+                (
+                    vec![("route", route), ("status", status.to_string())],
+                    count,
+                )
+            }),
+        );
+        family(
+            &mut out,
+            "wkd_key_lookups_total",
+            "counter",
+            "Key lookups by whether a key was found.",
+            [
+                ("hit", self.hits.load(Ordering::Relaxed)),
+                ("miss", self.misses.load(Ordering::Relaxed)),
+            ]
+            .map(|(result, count)| (vec![("result", result.to_string())], count)),
+        );
+
+        let mut domains = BTreeMap::<&str, u64>::new();
+        for key in keys {
+            *domains.entry(&key.key.domain).or_default() += 1;
+        }
+        family(
+            &mut out,
+            "wkd_keys",
+            "gauge",
+            "Keys served per domain, counting every address of a key.",
+            domains
+                .into_iter()
+                .map(|(domain, count)| (vec![("domain", domain.to_string())], count)),
+        );
+
+        // a key is listed once per address, but expires only once
+        let expirations = keys
+            .iter()
+            .filter_map(|key| Some((key.fingerprint.to_hex(), key.expiration?)))
+            .collect::<BTreeMap<_, _>>();
+        let now = SystemTime::now();
+        family(
+            &mut out,
+            "wkd_key_days_until_expiry",
+            "gauge",
+            "Days until a served key expires, keys without expiration are omitted.",
+            expirations.into_iter().map(|(fingerprint, expiration)| {
+                let days = match expiration.duration_since(now) {
+                    Ok(remaining) => remaining.as_secs_f64() / SECONDS_PER_DAY,
+                    Err(e) => -e.duration().as_secs_f64() / SECONDS_PER_DAY,
+                };
+                (vec![("fingerprint", fingerprint)], format!("{days:.3}"))
+            }),
+        );
+
+        for (name, help, value) in [
+            (
+                "wkd_watcher_events_total",
+                "File system events handled by the watcher.",
+                stats.watcher_events,
+            ),
+            (
+                "wkd_watcher_errors_total",
+                "Errors reported by the file system watcher.",
+                stats.watcher_errors,
+            ),
+            (
+                "wkd_load_errors_total",
+                "Key files and keys in them that could not be read.",
+                stats.load_errors,
+            ),
+        ] {
+            family(&mut out, name, "counter", help, [(vec![], value)]);
+        }
+        family(
+            &mut out,
+            "wkd_secret_key_files",
+            "gauge",
+            "Files in the keys path refused because they contain secret key material.",
+            [(vec![], stats.secret_key_files)],
+        );
+
+        out
+    }
+}
+
+/// Writes a metric family with its samples, which consist of label pairs and a value.
+fn family<V: Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (Vec<(&'static str, String)>, V)>,
+) {
+    // writing into a string cannot fail
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name} {value}");
+        } else {
+            let _ = writeln!(out, "{name}{{{}}} {value}", labels.join(","));
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts requests by their route, so that the hashes in paths do not end up in labels.
+pub async fn track_requests(
+    State(state): State<ApiContext>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let response = next.run(request).await;
+    state.metrics.record_request(&route, response.status());
+    response
+}
+
+async fn get_metrics(State(state): State<ApiContext>) -> Result<impl IntoResponse, ApiError> {
+    let (keys, stats) = state
+        .with_key_store(|store| Ok((store.list()?, store.stats())))
+        .await?;
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&keys, &stats),
+    ))
+}
+
+pub fn router() -> Router<ApiContext> {
+    Router::new().route("/metrics", get(get_metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::metrics::Metrics;
+    use crate::keys::{CertKey, StoreStats, StoredKey};
+    use axum::http::StatusCode;
+    use sequoia_openpgp::cert::CertBuilder;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.record_request("/.well-known/openpgpkey/hu/{key}", StatusCode::OK);
+        metrics.record_request("/.well-known/openpgpkey/hu/{key}", StatusCode::OK);
+        metrics.record_lookup(false);
+
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let key = StoredKey {
+            key: CertKey {
+                hashed_username: "kei1q4tipxxu1yj79k9kfukdhfy631xe".to_string(),
+                domain: "example.com".to_string(),
+            },
+            username: "alice".to_string(),
+            fingerprint: cert.fingerprint(),
+            source: "/keys/alice.asc".to_string(),
+            expiration: Some(SystemTime::now() + Duration::from_secs(86400 * 10 + 60)),
+        };
+
+        let stats = StoreStats {
+            secret_key_files: 1,
+            ..StoreStats::default()
+        };
+        let rendered = metrics.render(&[key], &stats);
+        assert!(rendered.contains(
+            "wkd_http_requests_total{route=\"/.well-known/openpgpkey/hu/{key}\",status=\"200\"} 2\n"
+        ));
+        assert!(rendered.contains("wkd_key_lookups_total{result=\"miss\"} 1\n"));
+        assert!(rendered.contains("wkd_keys{domain=\"example.com\"} 1\n"));
+        assert!(rendered.contains(&format!(
+            "wkd_key_days_until_expiry{{fingerprint=\"{}\"}} 10.0",
+            cert.fingerprint().to_hex()
+        )));
+        assert!(rendered.contains("wkd_load_errors_total 0\n"));
+        assert!(rendered.contains("wkd_secret_key_files 1\n"));
+    }
+}
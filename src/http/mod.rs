@@ -1,46 +1,169 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Context;
-use axum::Router;
+use axum::{Router, middleware};
 use tokio::signal;
 use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{error, info};
 
 use crate::config::Config;
-use crate::keys::KeyDb;
+use crate::domain::normalize_domain;
+use crate::http::errors::ApiError;
+use crate::http::host::PeerAddr;
+use crate::http::metrics::Metrics;
+use crate::http::tls::{CertFiles, TlsListener};
+use crate::keys::{KeyDb, KeyOptions, KeyStore, SqliteStore, StoreKind};
+use crate::submission::{Mailer, Submissions};
 
+pub mod admin;
 pub mod errors;
+pub mod health;
 pub mod host;
 pub mod keys;
+pub mod metrics;
 pub mod policy;
+pub mod submission;
+pub mod tls;
 
 #[derive(Clone)]
 pub struct ApiContext {
     config: Arc<Config>,
-    key_db: Arc<KeyDb>,
+    key_options: Arc<KeyOptions>,
+    key_store: Arc<dyn KeyStore>,
+    submissions: Arc<Submissions>,
+    /// Sends the challenges of submissions, set if submission is enabled.
+    mailer: Option<Arc<Mailer>>,
+    metrics: Arc<Metrics>,
+}
+
+impl ApiContext {
+    /// Normalizes the domain of a request, see [`normalize_domain`]. Requests for domains the server is not
+    /// authoritative for are answered with 404, without looking up anything.
+    fn served_domain(&self, domain: &str) -> Result<String, ApiError> {
+        let domain =
+            normalize_domain(domain).map_err(|e| ApiError::BadRequest(format!("{e:#}")))?;
+        if self.key_options.domains.allows(&domain) {
+            Ok(domain)
+        } else {
+            info!("Not serving domain {domain}.");
+            Err(ApiError::NotFound)
+        }
+    }
+
+    /// Runs `f` with the key store on a thread for blocking operations. Key stores read files or query a database,
+    /// which must not stall the runtime.
+    async fn with_key_store<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn KeyStore) -> anyhow::Result<T> + Send + 'static,
+    ) -> Result<T, ApiError> {
+        let key_store = self.key_store.clone();
+        let result = tokio::task::spawn_blocking(move || f(key_store.as_ref()))
+            .await
+            .map_err(anyhow::Error::from)??;
+        Ok(result)
+    }
 }
 
 pub async fn serve(config: Config) -> anyhow::Result<()> {
     let socket_addr: SocketAddr = format!("{}:{}", config.address, config.port)
         .as_str()
         .parse()?;
-    let cache = KeyDb::new(Path::new(&config.keys_path), config.split_keys).await?;
-    let app = api_router()
-        .with_state(ApiContext {
-            config: Arc::new(config),
-            key_db: Arc::new(cache),
-        })
+    let keys_path = Path::new(&config.keys.keys_path);
+    let key_options = config.keys.key_options()?;
+    let key_store: Arc<dyn KeyStore> = match config.store {
+        StoreKind::Directory => Arc::new(KeyDb::new(keys_path, key_options.clone()).await?),
+        StoreKind::Sqlite => Arc::new(SqliteStore::new(keys_path, key_options.clone())?),
+    };
+    let secret_key_files = key_store.secret_key_files();
+    if !secret_key_files.is_empty() {
+        error!(
+            "{} files in the keys path contain secret key material and are not served: {}",
+            secret_key_files.len(),
+            secret_key_files.join(", ")
+        );
+    }
+    let mailer = match (&config.smtp_url, &config.mail_from) {
+        (Some(url), Some(from)) if config.submission => Some(Arc::new(Mailer::new(url, from)?)),
+        _ => None,
+    };
+    let state = ApiContext {
+        config: Arc::new(config),
+        key_options: Arc::new(key_options),
+        key_store,
+        submissions: Arc::new(Submissions::default()),
+        mailer,
+        metrics: Arc::new(Metrics::default()),
+    };
+    let app = api_router(&state.config)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_requests,
+        ))
+        // probes are neither counted nor routed by host
+        .merge(health::router())
+        .with_state(state.clone())
         .layer(CorsLayer::permissive())
         .layer(TraceLayer::new_for_http());
 
-    info!("WKD server listening on {}", socket_addr);
-    let listener = tokio::net::TcpListener::bind(socket_addr).await?;
-    axum::serve(listener, app.into_make_service())
+    if let Some(metrics_address) = state.config.metrics_address {
+        let listener = tokio::net::TcpListener::bind(metrics_address).await?;
+        info!("Metrics listening on {}", metrics_address);
+        let metrics_app = metrics::router()
+            .merge(health::router())
+            .with_state(state.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, metrics_app).await {
+                error!("error running metrics server: {:?}", e);
+            }
+        });
+    }
+
+    if let Some(admin_address) = state.config.admin_address {
+        let listener = tokio::net::TcpListener::bind(admin_address).await?;
+        info!("Admin API listening on {}", admin_address);
+        let admin_app = admin::router(state.clone())
+            .with_state(state.clone())
+            .layer(TraceLayer::new_for_http());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, admin_app).await {
+                error!("error running admin server: {:?}", e);
+            }
+        });
+    }
+
+    if state.config.tls_cert.is_empty() {
+        info!("WKD server listening on {}", socket_addr);
+        let listener = tokio::net::TcpListener::bind(socket_addr).await?;
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<PeerAddr>(),
+        )
         .with_graceful_shutdown(shutdown_signal())
         .await
         .context("error running HTTP server")
+    } else {
+        let files = state
+            .config
+            .tls_cert
+            .iter()
+            .zip(&state.config.tls_key)
+            .map(|(cert, key)| CertFiles {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+            })
+            .collect();
+        info!("WKD server listening on {} with TLS", socket_addr);
+        let listener = TlsListener::bind(socket_addr, files).await?;
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<PeerAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .context("error running HTTPS server")
+    }
 }
 
 async fn shutdown_signal() {
@@ -67,6 +190,12 @@ async fn shutdown_signal() {
     }
 }
 
-fn api_router() -> Router<ApiContext> {
-    keys::router().merge(policy::router())
+fn api_router(config: &Config) -> Router<ApiContext> {
+    let router = keys::router().merge(policy::router());
+
+    if config.submission {
+        router.merge(submission::router())
+    } else {
+        router
+    }
 }
@@ -1,30 +1,40 @@
 use crate::http::ApiContext;
 use crate::http::errors::ApiError;
-use crate::http::host::domain_from_headers;
-use crate::policy::get_policy;
+use crate::http::host::{PeerAddr, domain_from_headers};
+use crate::policy::{get_submission_address, policy_for_domain};
 use axum::Router;
-use axum::extract::{Path, State};
+use axum::extract::{ConnectInfo, Path, State};
 use axum::http::HeaderMap;
 use axum::routing::get;
 
 type PolicyResponse = String;
 
-const EMPTY_POLICY: &str = "# Empty policy\n";
-
 fn get_policy_for_domain(state: &ApiContext, domain: &str) -> Result<PolicyResponse, ApiError> {
-    match &state.config.policy {
-        None => Ok(EMPTY_POLICY.into()),
-        Some(path) => Ok(get_policy(path, domain)
-            .map_err(|_| ApiError::Internal("".into()))?
-            .unwrap_or_default()),
-    }
+    let domain = &state.served_domain(domain)?;
+    policy_for_domain(state.config.keys.policy.as_deref(), domain)
+        .map_err(|_| ApiError::Internal("".into()))
+}
+
+fn get_submission_address_for_domain(
+    state: &ApiContext,
+    domain: &str,
+) -> Result<PolicyResponse, ApiError> {
+    let domain = &state.served_domain(domain)?;
+    let Some(path) = &state.config.keys.policy else {
+        return Err(ApiError::NotFound);
+    };
+
+    get_submission_address(path, domain)
+        .map_err(|_| ApiError::Internal("".into()))?
+        .ok_or(ApiError::NotFound)
 }
 
 pub async fn get_policy_direct(
     State(state): State<ApiContext>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
     headers: HeaderMap,
 ) -> Result<PolicyResponse, ApiError> {
-    let domain = domain_from_headers(&headers)?;
+    let domain = domain_from_headers(&headers, peer.ip(), &state.config.trusted_proxy)?;
     get_policy_for_domain(&state, &domain)
 }
 
@@ -35,6 +45,22 @@ pub async fn get_policy_advanced(
     get_policy_for_domain(&state, &domain)
 }
 
+pub async fn get_submission_address_direct(
+    State(state): State<ApiContext>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    headers: HeaderMap,
+) -> Result<PolicyResponse, ApiError> {
+    let domain = domain_from_headers(&headers, peer.ip(), &state.config.trusted_proxy)?;
+    get_submission_address_for_domain(&state, &domain)
+}
+
+pub async fn get_submission_address_advanced(
+    State(state): State<ApiContext>,
+    Path(domain): Path<String>,
+) -> Result<PolicyResponse, ApiError> {
+    get_submission_address_for_domain(&state, &domain)
+}
+
 pub fn router() -> Router<ApiContext> {
     Router::new()
         .route("/.well-known/openpgpkey/policy", get(get_policy_direct))
@@ -42,4 +68,12 @@ pub fn router() -> Router<ApiContext> {
             "/.well-known/openpgpkey/{domain}/policy",
             get(get_policy_advanced),
         )
+        .route(
+            "/.well-known/openpgpkey/submission-address",
+            get(get_submission_address_direct),
+        )
+        .route(
+            "/.well-known/openpgpkey/{domain}/submission-address",
+            get(get_submission_address_advanced),
+        )
 }
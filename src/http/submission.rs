@@ -0,0 +1,226 @@
+use crate::http::ApiContext;
+use crate::http::errors::ApiError;
+use crate::http::host::{PeerAddr, domain_from_headers};
+use crate::keys::{
+    CertKey, KeyStoreEvent, cert_entries, key_file_path, parse_cert, parse_certs,
+    retain_address_userids, retain_served_userids,
+};
+use crate::submission::{LimitExceeded, Submission};
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, State};
+use axum::http::HeaderMap;
+use axum::routing::post;
+use sequoia_openpgp::{Cert, Fingerprint};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::timeout;
+use tracing::{error, info, warn};
+
+/// How long to wait for a published key to be picked up by the key store.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Makes sure that no other certificate is currently served for any address of `cert`.
+/// Updates to an already published certificate are allowed, as is replacing the certificate `replaced`.
+pub async fn check_conflicts(
+    state: &ApiContext,
+    cert: &Cert,
+    replaced: Option<&Fingerprint>,
+) -> Result<Vec<CertKey>, ApiError> {
+    let keys_path = Path::new(&state.config.keys.keys_path);
+    let entries = cert_entries(
+        retain_served_userids(cert.clone(), &state.key_options),
+        &key_file_path(keys_path, cert),
+        false,
+        &state.key_options.validation,
+    )
+    .map_err(|e| ApiError::BadRequest(format!("Invalid certificate: {e:#}")))?;
+
+    let mut keys = Vec::new();
+    for (key, entry) in entries {
+        let fingerprints = {
+            let key = key.clone();
+            state
+                .with_key_store(move |store| store.fingerprints(&key))
+                .await?
+        };
+        if fingerprints
+            .iter()
+            .any(|fp| fp != &cert.fingerprint() && Some(fp) != replaced)
+        {
+            warn!(
+                "Rejecting submission of {} for '{}@{}', another key is already published.",
+                cert.fingerprint(),
+                entry.username,
+                key.domain
+            );
+            return Err(ApiError::Conflict(format!(
+                "A different key is already published for {}@{}.",
+                entry.username, key.domain
+            )));
+        }
+        keys.push(key);
+    }
+
+    Ok(keys)
+}
+
+pub async fn submit_key(
+    State(state): State<ApiContext>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<String, ApiError> {
+    let domain = domain_from_headers(&headers, peer.ip(), &state.config.trusted_proxy)?;
+    let domain = state.served_domain(&domain)?;
+    let Some(mailer) = &state.mailer else {
+        return Err(ApiError::Internal(
+            "Key submission is not configured.".into(),
+        ));
+    };
+    let Some(cert) = parse_cert(&body) else {
+        return Err(ApiError::BadRequest("Could not parse certificate.".into()));
+    };
+
+    let fingerprint = cert.fingerprint();
+    let mut addresses = Vec::new();
+    for (key, entry) in cert_entries(
+        retain_served_userids(cert.clone(), &state.key_options),
+        Path::new(""),
+        false,
+        &state.key_options.validation,
+    )
+    .map_err(|e| ApiError::BadRequest(format!("Invalid certificate: {e:#}")))?
+    {
+        if key.domain == domain && !addresses.contains(&(key.clone(), entry.username.clone())) {
+            addresses.push((key, entry.username));
+        }
+    }
+    if addresses.is_empty() {
+        return Err(ApiError::BadRequest(format!(
+            "Certificate does not contain a user ID for domain {domain}."
+        )));
+    }
+
+    // every address is confirmed on its own, only the user IDs of confirmed addresses are published
+    let confirm_url = format!("https://{domain}/.well-known/openpgpkey/submission/confirm");
+    let mut response = String::new();
+    for (key, username) in addresses {
+        let address = format!("{username}@{}", key.domain);
+        let cert = retain_address_userids(cert.clone(), &key, &username);
+        check_conflicts(&state, &cert, None).await?;
+
+        let challenge = state
+            .submissions
+            .submit(
+                Submission {
+                    address: address.clone(),
+                    cert,
+                },
+                &state.key_options.validation,
+            )
+            .map_err(|e| match e.downcast_ref::<LimitExceeded>() {
+                Some(limit) => ApiError::TooManyRequests(limit.to_string()),
+                None => ApiError::BadRequest(format!("{e:#}")),
+            })?;
+        mailer
+            .send_challenge(&address, &challenge, &confirm_url)
+            .await
+            .map_err(|e| {
+                error!("Could not send confirmation request: {:#}", e);
+                ApiError::Internal("Could not send the confirmation request.".into())
+            })?;
+        info!("Accepted submission of key {fingerprint} for {address}, confirmation request sent.");
+        response.push_str(&format!("A confirmation request was sent to {address}.\n"));
+    }
+
+    Ok(response)
+}
+
+/// Returns `cert` merged with the user IDs of the same certificate that are already published, so that confirming
+/// one address of a certificate does not unpublish another one confirmed before.
+async fn merge_published(state: &ApiContext, mut cert: Cert) -> Result<Cert, ApiError> {
+    state
+        .with_key_store(move |store| {
+            let fingerprint = cert.fingerprint();
+            for stored in store.list()? {
+                if stored.fingerprint != fingerprint {
+                    continue;
+                }
+                let Some(published) = store.get(&stored.key, Some(&stored.username))? else {
+                    continue;
+                };
+                for published in parse_certs(&published).into_iter().flatten() {
+                    if published.fingerprint() == fingerprint {
+                        cert = cert.merge_public(published)?;
+                    }
+                }
+            }
+            Ok(cert)
+        })
+        .await
+}
+
+pub async fn confirm_key(
+    State(state): State<ApiContext>,
+    body: String,
+) -> Result<&'static str, ApiError> {
+    let Some(submission) = state.submissions.confirm(body.trim()) else {
+        return Err(ApiError::NotFound);
+    };
+
+    let cert = merge_published(&state, submission.cert).await?;
+    // another key might have been published while this submission was pending
+    let keys = check_conflicts(&state, &cert, None).await?;
+
+    let events = state.key_store.subscribe();
+    state.key_store.publish(&cert)?;
+    info!(
+        "Published submitted key {} for {}.",
+        cert.fingerprint(),
+        submission.address
+    );
+    wait_for_store(events, keys, &cert.fingerprint()).await;
+
+    Ok("Key published.\n")
+}
+
+/// Waits for the store to pick up a change of the certificate `fingerprint` for all `keys`,
+/// so it is served accordingly as soon as we respond. `events` must be subscribed before the change is made.
+pub async fn wait_for_store(
+    mut events: broadcast::Receiver<KeyStoreEvent>,
+    keys: impl IntoIterator<Item = CertKey>,
+    fingerprint: &Fingerprint,
+) {
+    let mut pending = keys.into_iter().collect::<HashSet<_>>();
+    let indexed = timeout(PUBLISH_TIMEOUT, async {
+        while !pending.is_empty() {
+            match events.recv().await {
+                Ok(event) => {
+                    pending.remove(&event.key);
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+    .await;
+    if indexed.is_err() {
+        warn!(
+            "Change of key {} was not picked up by the key store in time.",
+            fingerprint
+        );
+    }
+}
+
+pub fn router() -> Router<ApiContext> {
+    Router::new()
+        .route("/.well-known/openpgpkey/submission", post(submit_key))
+        .route(
+            "/.well-known/openpgpkey/submission/confirm",
+            post(confirm_key),
+        )
+}
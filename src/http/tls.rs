@@ -0,0 +1,309 @@
+use anyhow::{Context, Result, bail};
+use axum::serve::Listener;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, error, info, warn};
+
+/// How long a client may take for the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A certificate chain and the path of its private key, both PEM files.
+#[derive(Clone, Debug)]
+pub struct CertFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertFiles {
+    fn load(&self, provider: &CryptoProvider) -> Result<CertifiedKey> {
+        let chain = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Reading certificates from {}", self.cert.display()))?;
+        if chain.is_empty() {
+            bail!("No certificate found in {}", self.cert.display());
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .with_context(|| format!("Reading private key from {}", self.key.display()))?;
+
+        CertifiedKey::from_der(chain, key, provider).with_context(|| {
+            format!(
+                "Private key {} does not match certificate {}",
+                self.key.display(),
+                self.cert.display()
+            )
+        })
+    }
+}
+
+/// Selects the certificate for the server name requested by the client (SNI), falling back to the first one.
+#[derive(Debug)]
+struct CertResolver {
+    files: Vec<CertFiles>,
+    provider: Arc<CryptoProvider>,
+    keys: RwLock<Vec<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    fn new(files: Vec<CertFiles>, provider: Arc<CryptoProvider>) -> Result<Self> {
+        let keys = Self::load(&files, &provider)?;
+        Ok(Self {
+            files,
+            provider,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    fn load(files: &[CertFiles], provider: &CryptoProvider) -> Result<Vec<Arc<CertifiedKey>>> {
+        files
+            .iter()
+            .map(|files| files.load(provider).map(Arc::new))
+            .collect()
+    }
+
+    /// Loads all certificates again. If any of them cannot be loaded, e.g. because the renewal is still
+    /// writing the files, the previous certificates are kept.
+    fn reload(&self) {
+        match Self::load(&self.files, &self.provider) {
+            Ok(keys) => {
+                *self.keys.write().unwrap() = keys;
+                info!("Reloaded TLS certificates");
+            }
+            Err(e) => warn!(
+                "Could not reload TLS certificates, keeping the previous ones: {:#}",
+                e
+            ),
+        }
+    }
+}
+
+/// Whether the end-entity certificate of `key` is valid for `name`, including wildcard names.
+fn valid_for(key: &CertifiedKey, name: &ServerName) -> bool {
+    key.end_entity_cert()
+        .ok()
+        .and_then(|cert| webpki::EndEntityCert::try_from(cert).ok())
+        .is_some_and(|cert| cert.verify_is_valid_for_subject_name(name).is_ok())
+}
+
+impl CertResolver {
+    /// Returns the first certificate valid for `name`, or the first certificate if none is.
+    fn select(&self, name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let keys = self.keys.read().unwrap();
+        let name = name.and_then(|name| ServerName::try_from(name).ok());
+
+        name.and_then(|name| keys.iter().find(|key| valid_for(key, &name)))
+            .or(keys.first())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.select(client_hello.server_name())
+    }
+}
+
+/// Reloads the certificates whenever one of their files changes. The directories containing them are watched,
+/// so that files replaced by renaming or by changing a symbolic link, as done by e.g. certbot, are noticed.
+fn watch(resolver: Arc<CertResolver>) -> Result<RecommendedWatcher> {
+    let paths = resolver
+        .files
+        .iter()
+        .flat_map(|files| [&files.cert, &files.key])
+        .map(std::path::absolute)
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    let watched = paths.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                let changed = matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) && event.paths.iter().any(|path| watched.contains(path));
+                if changed {
+                    resolver.reload();
+                }
+            }
+            Err(e) => error!("TLS certificate watch error: {:?}", e),
+        })?;
+
+    let dirs = paths
+        .iter()
+        .filter_map(|path| path.parent())
+        .collect::<HashSet<_>>();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    Ok(watcher)
+}
+
+/// Accepts TLS connections. Handshakes run concurrently, so that a slow client does not hold up others.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+    // certificates are reloaded as long as the watcher lives
+    _watcher: RecommendedWatcher,
+}
+
+impl TlsListener {
+    pub async fn bind(addr: SocketAddr, files: Vec<CertFiles>) -> Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let resolver = Arc::new(CertResolver::new(files, provider.clone())?);
+        let watcher = watch(resolver.clone())?;
+
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        // e.g. too many open files, retrying immediately would fail as well
+                        error!("Error accepting connection: {}", e);
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            // sending only fails if the server stopped
+                            let _ = sender.send((stream, peer)).await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => debug!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            connections,
+            local_addr,
+            _watcher: watcher,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // the accept loop never stops while the listener exists
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::tls::{CertFiles, CertResolver, valid_for};
+    use rcgen::{CertifiedKey, generate_simple_self_signed};
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    fn write_cert(dir: &Path, name: &str, subject: &str) -> CertFiles {
+        let CertifiedKey { cert, signing_key } =
+            generate_simple_self_signed(vec![subject.to_string()]).unwrap();
+        let files = CertFiles {
+            cert: dir.join(format!("{name}.crt")),
+            key: dir.join(format!("{name}.key")),
+        };
+        std::fs::write(&files.cert, cert.pem()).unwrap();
+        std::fs::write(&files.key, signing_key.serialize_pem()).unwrap();
+        files
+    }
+
+    #[test]
+    fn resolve() {
+        let dir = TempDir::new().unwrap();
+        let files = vec![
+            write_cert(dir.path(), "exact", "openpgpkey.example.com"),
+            write_cert(dir.path(), "wildcard", "*.example.org"),
+        ];
+        let resolver = CertResolver::new(files, Arc::new(ring::default_provider())).unwrap();
+
+        let keys = resolver.keys.read().unwrap().clone();
+        let name = |name: &'static str| ServerName::try_from(name).unwrap();
+        assert!(valid_for(&keys[0], &name("openpgpkey.example.com")));
+        assert!(!valid_for(&keys[0], &name("example.com")));
+        assert!(valid_for(&keys[1], &name("openpgpkey.example.org")));
+        assert!(!valid_for(&keys[1], &name("example.org")));
+        assert!(!valid_for(&keys[1], &name("a.openpgpkey.example.org")));
+
+        let select = |name| resolver.select(name).unwrap();
+        assert!(Arc::ptr_eq(
+            &select(Some("openpgpkey.example.com")),
+            &keys[0]
+        ));
+        assert!(Arc::ptr_eq(
+            &select(Some("openpgpkey.example.org")),
+            &keys[1]
+        ));
+        // unknown names and clients without SNI get the first certificate
+        assert!(Arc::ptr_eq(&select(Some("example.net")), &keys[0]));
+        assert!(Arc::ptr_eq(&select(None), &keys[0]));
+    }
+
+    #[test]
+    fn reload() {
+        let dir = TempDir::new().unwrap();
+        let files = write_cert(dir.path(), "server", "openpgpkey.example.com");
+        let resolver =
+            CertResolver::new(vec![files.clone()], Arc::new(ring::default_provider())).unwrap();
+        let previous = resolver.select(None).unwrap();
+
+        // a renewal that is still being written keeps the previous certificate
+        std::fs::write(&files.cert, "").unwrap();
+        resolver.reload();
+        assert!(Arc::ptr_eq(&resolver.select(None).unwrap(), &previous));
+
+        // a key not matching the certificate is refused as well
+        let other = write_cert(dir.path(), "other", "openpgpkey.example.com");
+        std::fs::copy(&other.cert, &files.cert).unwrap();
+        resolver.reload();
+        assert!(Arc::ptr_eq(&resolver.select(None).unwrap(), &previous));
+
+        std::fs::copy(&other.key, &files.key).unwrap();
+        resolver.reload();
+        assert!(!Arc::ptr_eq(&resolver.select(None).unwrap(), &previous));
+    }
+}
@@ -0,0 +1,67 @@
+use crate::config::ImportConfig;
+use crate::keys::{
+    ConflictResolution, key_files, open_database, read_keys, remove_stale_sources, replace_source,
+};
+use anyhow::{Result, bail};
+use std::collections::HashSet;
+use std::path::Path;
+use tracing::{debug, error};
+
+/// Reads all key files from the keys path into the database.
+///
+/// Files that were imported before are replaced, and keys of files that no longer exist are removed,
+/// so an import can be repeated whenever the keys path changed. Published keys are kept.
+pub fn import(config: &ImportConfig) -> Result<()> {
+    let keys_path = Path::new(&config.keys_path);
+    if !keys_path.is_dir() {
+        bail!("Keys path '{}' is not a directory.", config.keys_path);
+    }
+    // sources are stored as absolute paths, so the database can be used from any working directory
+    let keys_path = keys_path.canonicalize()?;
+    // conflicts are resolved when serving
+    let options = config.index.key_options(ConflictResolution::ServeAll);
+
+    let mut conn = open_database(Path::new(&config.database))?;
+    let tx = conn.transaction()?;
+
+    let mut seen = HashSet::new();
+    let mut keys = 0;
+    let mut secret_key_files = 0;
+    for file in key_files(&keys_path, &options)? {
+        let entries = match read_keys(&keys_path, &file, &options) {
+            Ok(file) if file.secret_keys => {
+                secret_key_files += 1;
+                continue;
+            }
+            Ok(file) => file.entries,
+            Err(e) => {
+                error!("error importing file {}: {:?}", file.to_string_lossy(), e);
+                continue;
+            }
+        };
+        debug!(
+            "Importing {} entries from {}",
+            entries.len(),
+            file.to_string_lossy()
+        );
+
+        let source = file.to_string_lossy().to_string();
+        replace_source(&tx, &source, &entries)?;
+        keys += entries.len();
+        seen.insert(source);
+    }
+    let removed = remove_stale_sources(&tx, &keys_path, &seen)?;
+
+    tx.commit()?;
+
+    println!(
+        "Imported {keys} keys from {} files into {}, removed keys of {removed} deleted files",
+        seen.len(),
+        config.database
+    );
+    if secret_key_files > 0 {
+        bail!("{secret_key_files} files contain secret key material and were not imported");
+    }
+
+    Ok(())
+}
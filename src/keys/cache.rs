@@ -0,0 +1,390 @@
+use crate::keys::conflict::ConflictResolution;
+use crate::keys::db::{CertEntry, CertKey};
+use crate::keys::fs::{
+    entry_rejection, files_below, is_temporary_file, key_files, read_keys, within_depth,
+};
+use crate::keys::store::StoredKey;
+use crate::keys::{KeyLayout, KeyOptions};
+use anyhow::{Context, Result};
+use sequoia_openpgp::serialize::SerializeInto;
+use sequoia_openpgp::{Cert, Fingerprint};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, error, info, warn};
+
+/// In-memory index of all keys read from the key path.
+pub struct Cache {
+    /// All entries for an address, ordered by file path and fingerprint.
+    entries: HashMap<CertKey, Vec<CertEntry>>,
+    /// Files that have to be read again at the given time, because the validity of a certificate changes.
+    transitions: HashMap<PathBuf, SystemTime>,
+    /// Files refused because they contain secret key material.
+    secret_key_files: HashSet<PathBuf>,
+    /// Number of files and keys that could not be read.
+    load_errors: u64,
+    key_path: PathBuf,
+    options: KeyOptions,
+}
+
+impl Cache {
+    pub fn new(key_path: &Path, options: KeyOptions) -> Self {
+        Self {
+            entries: HashMap::new(),
+            transitions: HashMap::new(),
+            secret_key_files: HashSet::new(),
+            load_errors: 0,
+            key_path: key_path.to_path_buf(),
+            options,
+        }
+    }
+
+    /// Reads all key files from the key path.
+    pub fn populate(&mut self) -> Result<()> {
+        info!(
+            "Populating keys db, key splitting enabled: {}",
+            self.options.split_keys
+        );
+
+        for file in key_files(&self.key_path, &self.options)? {
+            if let Err(e) = self.cache_file(&file) {
+                error!("error caching file: {:?}", e);
+                self.load_errors += 1;
+            }
+        }
+
+        info!(
+            "Populated db with {} keys for {} addresses",
+            self.entries.values().map(Vec::len).sum::<usize>(),
+            self.entries.len()
+        );
+
+        Ok(())
+    }
+
+    /// (Re-)reads a file, or all files of a directory moved into the key path,
+    /// and returns all addresses whose entries changed.
+    pub fn cache_path(&mut self, path: &Path) -> Result<Vec<CertKey>> {
+        let files = if path.is_dir() {
+            files_below(path, self.options.max_depth)?
+        } else if is_temporary_file(path) {
+            return Ok(Vec::new());
+        } else {
+            vec![path.to_path_buf()]
+        };
+
+        let mut changed = Vec::new();
+        for file in files {
+            // keep the same files populate reads
+            if self.options.layout == KeyLayout::UserIds
+                && !within_depth(&self.key_path, &file, self.options.max_depth)
+            {
+                info!(
+                    "Ignoring file {}, maximum depth exceeded",
+                    file.to_string_lossy()
+                );
+                continue;
+            }
+            match self.cache_file(&file) {
+                Ok(keys) => changed.extend(keys),
+                Err(e) => {
+                    error!("error caching file: {:?}", e);
+                    self.load_errors += 1;
+                }
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// (Re-)reads a file and returns all addresses whose entries changed.
+    pub fn cache_file(&mut self, path: &Path) -> Result<Vec<CertKey>> {
+        // first, we remove all files that might be in here still because of this path
+        let mut changed = self.remove_path(path);
+
+        let file = read_keys(&self.key_path, path, &self.options).context("Reading file")?;
+        self.load_errors += file.unreadable_keys;
+        if file.secret_keys {
+            self.secret_key_files.insert(path.to_path_buf());
+            return Ok(changed);
+        }
+        if let Some(time) = file.next_transition {
+            debug!(
+                "Reading file {} again at {}",
+                path.to_string_lossy(),
+                humantime::format_rfc3339_seconds(time)
+            );
+            self.transitions.insert(path.to_path_buf(), time);
+        }
+        let entries = file.entries;
+        if entries.is_empty() {
+            info!("Ignoring file {}, no entries found", path.to_string_lossy());
+            return Ok(changed);
+        }
+        entries.into_iter().for_each(|(entry, content)| {
+            if !changed.contains(&entry) {
+                changed.push(entry.clone());
+            }
+            let domain = entry.domain.clone();
+            info!(
+                "Adding key '{}@{}' from file {} to db",
+                content.username,
+                domain,
+                path.to_string_lossy()
+            );
+            let entries = self.entries.entry(entry).or_default();
+            for other in entries
+                .iter()
+                .filter(|other| other.cert.fingerprint() != content.cert.fingerprint())
+            {
+                warn!(
+                    "Conflict: '{}@{}' is claimed by key {} from file {} and by key {} from file {}",
+                    content.username,
+                    domain,
+                    content.cert.fingerprint(),
+                    path.to_string_lossy(),
+                    other.cert.fingerprint(),
+                    other.path.to_string_lossy()
+                );
+            }
+            entries.push(content);
+            entries.sort_by(|a, b| {
+                a.path
+                    .cmp(&b.path)
+                    .then_with(|| a.cert.fingerprint().cmp(&b.cert.fingerprint()))
+            });
+        });
+        Ok(changed)
+    }
+
+    /// Removes all entries read from a file, or from any file below a directory,
+    /// and returns the affected addresses.
+    pub fn remove_path(&mut self, path: &Path) -> Vec<CertKey> {
+        let mut changed = Vec::new();
+
+        self.transitions.retain(|file, _| !file.starts_with(path));
+        self.secret_key_files.retain(|file| !file.starts_with(path));
+        // we remove all items that were inserted into the map because of this file.
+        self.entries.retain(|key, entries| {
+            let len = entries.len();
+            entries.retain(|entry| !Path::new(&entry.path).starts_with(path));
+            if entries.len() != len {
+                changed.push(key.clone());
+            }
+            !entries.is_empty()
+        });
+
+        changed
+    }
+
+    /// Returns the earliest time at which a file has to be read again, see [`Cache::read_due_files`].
+    pub fn next_transition(&self) -> Option<SystemTime> {
+        self.transitions.values().min().copied()
+    }
+
+    /// Reads all files again whose next validity transition has been reached,
+    /// and returns all addresses whose entries changed.
+    pub fn read_due_files(&mut self) -> Vec<CertKey> {
+        let now = SystemTime::now();
+        let due = self
+            .transitions
+            .iter()
+            .filter(|&(_, &time)| time <= now)
+            .map(|(file, _)| file.clone())
+            .collect::<Vec<_>>();
+
+        let mut changed = Vec::new();
+        for file in due {
+            info!(
+                "Reading file {} again, the validity of a key changed",
+                file.to_string_lossy()
+            );
+            match self.cache_file(&file) {
+                Ok(keys) => changed.extend(keys),
+                Err(e) => {
+                    error!("error caching file: {:?}", e);
+                    self.load_errors += 1;
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Removes all entries that are no longer valid, e.g. because they expired since they were read,
+    /// and returns the affected addresses.
+    pub fn revalidate(&mut self) -> Vec<CertKey> {
+        let mut changed = Vec::new();
+
+        self.entries.retain(|key, entries| {
+            let len = entries.len();
+            entries.retain(|entry| {
+                let Some(reason) = entry_rejection(key, entry, &self.options.validation) else {
+                    return true;
+                };
+                info!(
+                    "Removing key '{}@{}' from file {} from db: {}",
+                    entry.username,
+                    key.domain,
+                    entry.path.to_string_lossy(),
+                    reason
+                );
+                false
+            });
+            if entries.len() != len {
+                changed.push(key.clone());
+            }
+            !entries.is_empty()
+        });
+
+        changed
+    }
+
+    /// Returns all files that were refused because they contain secret key material.
+    pub fn secret_key_files(&self) -> Vec<PathBuf> {
+        let mut files = self.secret_key_files.iter().cloned().collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    /// Returns the number of files and keys that could not be read since the cache was created.
+    pub fn load_errors(&self) -> u64 {
+        self.load_errors
+    }
+
+    pub fn list(&self) -> Vec<StoredKey> {
+        self.entries
+            .iter()
+            .flat_map(|(key, entries)| {
+                entries.iter().map(|entry| StoredKey {
+                    key: key.clone(),
+                    username: entry.username.clone(),
+                    fingerprint: entry.cert.fingerprint(),
+                    source: entry.path.to_string_lossy().to_string(),
+                    expiration: entry.expiration,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the fingerprints of all certificates currently served for `key`.
+    pub fn fingerprints(&self, key: &CertKey) -> Vec<Fingerprint> {
+        self.entries
+            .get(key)
+            .into_iter()
+            .flatten()
+            .map(|entry| entry.cert.fingerprint())
+            .collect()
+    }
+
+    /// Returns all certificates for the given address, see [`serve_entries`].
+    pub fn get(&self, key: &CertKey, username: Option<&str>) -> Result<Option<Vec<u8>>> {
+        let entries = self.entries.get(key).cloned().unwrap_or_default();
+        serve_entries(key, entries, username, &self.options.conflicts)
+    }
+}
+
+/// Serializes the entries served for an address: entries not matching `username` are dropped,
+/// conflicts are resolved and all remaining certificates are concatenated in the given order.
+/// Certificates with the same fingerprint found in multiple files are merged.
+pub fn serve_entries(
+    key: &CertKey,
+    entries: Vec<CertEntry>,
+    username: Option<&str>,
+    conflicts: &ConflictResolution,
+) -> Result<Option<Vec<u8>>> {
+    let domain = &key.domain;
+
+    let entries = match username {
+        Some(requested) => {
+            let (matching, other): (Vec<_>, Vec<_>) = entries
+                .into_iter()
+                .partition(|entry| entry.username == requested);
+            if let Some(CertEntry { username, .. }) = other.first() {
+                info!(
+                    "hash matched for '{username}@{domain}', but requested local part '{requested}' did not match. Ignoring."
+                );
+            }
+            matching
+        }
+        None => entries,
+    };
+
+    // conflicts are logged when the entries are indexed, not on every request
+    let entries = conflicts.resolve(entries);
+
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    let mut certs: Vec<Cert> = Vec::new();
+    for entry in entries {
+        match certs
+            .iter_mut()
+            .find(|cert| cert.fingerprint() == entry.cert.fingerprint())
+        {
+            Some(cert) => *cert = cert.clone().merge_public(entry.cert)?,
+            None => certs.push(entry.cert),
+        }
+    }
+
+    let mut response = Vec::new();
+    for cert in certs {
+        response.extend(cert.to_vec()?);
+    }
+
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::cache::Cache;
+    use crate::keys::{KeyOptions, mail_to_key_entry, parse_certs};
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::serialize::SerializeInto;
+    use tempfile::TempDir;
+
+    #[test]
+    fn multiple_certs() {
+        let dir = TempDir::new().unwrap();
+        let (old, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .add_userid("alias@example.com")
+            .generate()
+            .unwrap();
+        let (new, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let only_alice = old
+            .clone()
+            .retain_userids(|userid| userid.userid().value() == b"alice@example.com");
+
+        // the old key is split across two files, which are read in a different order than they are named
+        std::fs::write(dir.path().join("c.asc"), old.armored().to_vec().unwrap()).unwrap();
+        std::fs::write(dir.path().join("b.asc"), new.armored().to_vec().unwrap()).unwrap();
+        std::fs::write(
+            dir.path().join("a.asc"),
+            only_alice.armored().to_vec().unwrap(),
+        )
+        .unwrap();
+
+        let mut cache = Cache::new(dir.path(), KeyOptions::default());
+        for file in ["c.asc", "b.asc", "a.asc"] {
+            cache.cache_file(&dir.path().join(file)).unwrap();
+        }
+        let (_, key) = mail_to_key_entry("alice@example.com").unwrap().unwrap();
+        let served = parse_certs(&cache.get(&key, None).unwrap().unwrap())
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        // ordered by file name, the old key from a.asc and c.asc is merged into one certificate
+        assert_eq!(
+            served
+                .iter()
+                .map(|cert| cert.fingerprint())
+                .collect::<Vec<_>>(),
+            [old.fingerprint(), new.fingerprint()]
+        );
+        assert_eq!(served[0].userids().count(), 2);
+    }
+}
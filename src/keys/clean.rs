@@ -0,0 +1,155 @@
+use crate::keys::validation::Validation;
+use anyhow::Result;
+use clap::ValueEnum;
+use sequoia_openpgp::cert::amalgamation::ValidAmalgamation;
+use sequoia_openpgp::types::RevocationStatus;
+use sequoia_openpgp::{Cert, Packet};
+
+/// How certificates are cleaned before they are served, similar to GnuPG's `export-clean` and `export-minimal`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CleanMode {
+    /// Serve certificates as they are.
+    #[default]
+    None,
+    /// Drop user attributes (e.g. photo IDs), invalid user IDs, superseded self-signatures and expired or revoked subkeys.
+    Clean,
+    /// Like `clean`, and additionally drop all third-party certifications.
+    Minimal,
+}
+
+/// Rebuilds `cert` with only the components and signatures kept by `mode`.
+/// Revocations issued by the certificate itself are always kept.
+pub fn clean_cert(cert: Cert, mode: CleanMode, validation: &Validation) -> Result<Cert> {
+    if mode == CleanMode::None {
+        return Ok(cert);
+    }
+    let keep_certifications = mode == CleanMode::Clean;
+
+    let valid = cert.with_policy(validation.policy(), validation.time())?;
+
+    let primary = cert.primary_key();
+    let mut packets: Vec<Packet> = vec![primary.key().clone().into()];
+    packets.extend(primary.self_revocations().cloned().map(Packet::from));
+    if let Ok(signature) = valid.direct_key_signature() {
+        packets.push(signature.clone().into());
+    }
+    if keep_certifications {
+        packets.extend(primary.other_revocations().cloned().map(Packet::from));
+        packets.extend(primary.certifications().cloned().map(Packet::from));
+    }
+
+    for userid in valid.userids() {
+        packets.push(userid.userid().clone().into());
+        packets.push(userid.binding_signature().clone().into());
+        packets.extend(userid.self_revocations().cloned().map(Packet::from));
+        if keep_certifications {
+            packets.extend(userid.other_revocations().cloned().map(Packet::from));
+            packets.extend(userid.certifications().cloned().map(Packet::from));
+        }
+    }
+
+    for subkey in valid.keys().subkeys() {
+        if subkey.alive().is_err()
+            || matches!(subkey.revocation_status(), RevocationStatus::Revoked(_))
+        {
+            continue;
+        }
+        packets.push(subkey.key().clone().into());
+        packets.push(subkey.binding_signature().clone().into());
+    }
+
+    Cert::from_packets(packets.into_iter())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::clean::{CleanMode, clean_cert};
+    use crate::keys::validation::Validation;
+    use sequoia_openpgp::Packet;
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::packet::UserID;
+    use sequoia_openpgp::packet::signature::SignatureBuilder;
+    use sequoia_openpgp::types::SignatureType;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn third_party_certifications() {
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .set_creation_time(SystemTime::now() - Duration::from_secs(3600))
+            .generate()
+            .unwrap();
+        let (certifier, _) = CertBuilder::general_purpose(Some("bob@example.com"))
+            .generate()
+            .unwrap();
+
+        let mut signer = certifier
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()
+            .unwrap()
+            .into_keypair()
+            .unwrap();
+        let userid = UserID::from("alice@example.com");
+        let certification = userid
+            .certify(
+                &mut signer,
+                &cert,
+                SignatureType::GenericCertification,
+                None,
+                None,
+            )
+            .unwrap();
+        let (cert, _) = cert
+            .insert_packets(vec![Packet::from(userid.clone()), certification.into()])
+            .unwrap();
+
+        // a superseded self-signature
+        let mut signer = cert
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()
+            .unwrap()
+            .into_keypair()
+            .unwrap();
+        let template = cert
+            .userids()
+            .next()
+            .unwrap()
+            .self_signatures()
+            .next()
+            .unwrap()
+            .clone();
+        let binding = userid
+            .bind(&mut signer, &cert, SignatureBuilder::from(template))
+            .unwrap();
+        let (cert, _) = cert
+            .insert_packets(vec![Packet::from(userid), binding.into()])
+            .unwrap();
+
+        let signatures = |cert: &sequoia_openpgp::Cert| {
+            let userid = cert.userids().next().unwrap();
+            (
+                userid.self_signatures().count(),
+                userid.certifications().count(),
+            )
+        };
+
+        assert_eq!(signatures(&cert), (2, 1));
+        assert_eq!(
+            signatures(&clean_cert(cert.clone(), CleanMode::None, &Validation::default()).unwrap()),
+            (2, 1)
+        );
+        assert_eq!(
+            signatures(
+                &clean_cert(cert.clone(), CleanMode::Clean, &Validation::default()).unwrap()
+            ),
+            (1, 1)
+        );
+        assert_eq!(
+            signatures(&clean_cert(cert, CleanMode::Minimal, &Validation::default()).unwrap()),
+            (1, 0)
+        );
+    }
+}
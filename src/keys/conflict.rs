@@ -0,0 +1,152 @@
+use crate::keys::db::CertEntry;
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+/// How to serve an address that is claimed by more than one certificate.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Serve all certificates, e.g. during a key rotation.
+    ServeAll,
+    /// Serve none of the conflicting certificates.
+    Reject,
+    /// Serve the certificate with the newest creation time.
+    Newest,
+    /// Serve the certificates from the preferred directory, reject if there are none.
+    PreferDirectory,
+}
+
+#[derive(Clone, Debug)]
+pub enum ConflictResolution {
+    ServeAll,
+    Reject,
+    Newest,
+    PreferDirectory(PathBuf),
+}
+
+impl ConflictResolution {
+    /// Picks the entries to serve out of all entries for an address.
+    /// Entries without a conflict, i.e., all sharing the same fingerprint, are returned unchanged.
+    pub fn resolve(&self, entries: Vec<CertEntry>) -> Vec<CertEntry> {
+        if !has_conflict(&entries) {
+            return entries;
+        }
+
+        match self {
+            ConflictResolution::ServeAll => entries,
+            ConflictResolution::Reject => vec![],
+            ConflictResolution::Newest => {
+                let Some(newest) = entries
+                    .iter()
+                    .map(|entry| {
+                        (
+                            entry.cert.primary_key().key().creation_time(),
+                            entry.cert.fingerprint(),
+                        )
+                    })
+                    .max()
+                    .map(|(_, fingerprint)| fingerprint)
+                else {
+                    return vec![];
+                };
+
+                entries
+                    .into_iter()
+                    .filter(|entry| entry.cert.fingerprint() == newest)
+                    .collect()
+            }
+            ConflictResolution::PreferDirectory(dir) => entries
+                .into_iter()
+                .filter(|entry| Path::new(&entry.path).starts_with(dir))
+                .collect(),
+        }
+    }
+}
+
+/// Returns whether the entries belong to more than one certificate.
+pub fn has_conflict(entries: &[CertEntry]) -> bool {
+    entries.split_first().is_some_and(|(first, rest)| {
+        rest.iter()
+            .any(|entry| entry.cert.fingerprint() != first.cert.fingerprint())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::conflict::ConflictResolution;
+    use crate::keys::db::CertEntry;
+    use sequoia_openpgp::cert::CertBuilder;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    fn entry(path: &str, created: SystemTime) -> CertEntry {
+        let (cert, _) = CertBuilder::general_purpose(Some("m@example.com"))
+            .set_creation_time(created)
+            .generate()
+            .unwrap();
+        CertEntry {
+            username: "m".into(),
+            cert,
+            path: path.into(),
+            expiration: None,
+        }
+    }
+
+    fn paths(entries: Vec<CertEntry>) -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|entry| entry.path.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn no_conflict() {
+        let old = entry(
+            "/keys/old.asc",
+            SystemTime::now() - Duration::from_secs(3600),
+        );
+        let copy = CertEntry {
+            path: "/keys/copy.asc".into(),
+            ..old.clone()
+        };
+
+        assert_eq!(
+            paths(ConflictResolution::Reject.resolve(vec![old, copy])),
+            vec!["/keys/old.asc", "/keys/copy.asc"]
+        );
+    }
+
+    #[test]
+    fn resolve_conflict() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry("/keys/trusted/old.asc", now - Duration::from_secs(3600)),
+            entry("/keys/new.asc", now - Duration::from_secs(60)),
+        ];
+
+        assert_eq!(
+            paths(ConflictResolution::ServeAll.resolve(entries.clone())),
+            vec!["/keys/trusted/old.asc", "/keys/new.asc"]
+        );
+        assert!(
+            ConflictResolution::Reject
+                .resolve(entries.clone())
+                .is_empty()
+        );
+        assert_eq!(
+            paths(ConflictResolution::Newest.resolve(entries.clone())),
+            vec!["/keys/new.asc"]
+        );
+        assert_eq!(
+            paths(
+                ConflictResolution::PreferDirectory(PathBuf::from("/keys/trusted"))
+                    .resolve(entries.clone())
+            ),
+            vec!["/keys/trusted/old.asc"]
+        );
+        assert!(
+            ConflictResolution::PreferDirectory(PathBuf::from("/other"))
+                .resolve(entries)
+                .is_empty()
+        );
+    }
+}
@@ -1,20 +1,24 @@
-use crate::keys::fs::read_key_file;
-use anyhow::{Context, Result, bail};
+use crate::keys::KeyOptions;
+use crate::keys::cache::Cache;
+use crate::keys::fs::{remove_from_key_file, write_key_file};
+use crate::keys::store::{KeyStore, KeyStoreEvent, StoreStats, StoredKey, notify_changed};
+use anyhow::{Result, bail};
 use notify::event::{CreateKind, ModifyKind, RemoveKind};
 use notify::{EventKind, RecommendedWatcher, Watcher};
-use sequoia_openpgp::Cert;
-use sequoia_openpgp::serialize::SerializeInto;
-use std::collections::HashMap;
+use sequoia_openpgp::{Cert, Fingerprint};
+use std::collections::HashSet;
 use std::ffi::OsString;
 use std::hash::Hash;
-use std::path::Path;
-use std::sync::Arc;
-use std::sync::mpsc::channel;
-use tokio::sync::{RwLock, RwLockWriteGuard};
-use tokio::{fs, task};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
 use tracing::{debug, error, info};
 
-#[derive(Eq, PartialEq, Hash, Debug)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
 pub struct CertKey {
     pub hashed_username: String,
     pub domain: String,
@@ -25,167 +29,349 @@ pub struct CertEntry {
     pub username: String,
     pub cert: Cert,
     pub path: OsString,
+    /// When the certificate expires, `None` if it does not. Determined when the entry is read, so that
+    /// listing entries does not have to validate every certificate again.
+    pub expiration: Option<SystemTime>,
 }
 
-type Cache = HashMap<CertKey, CertEntry>;
+/// After this many errors in a row, the watcher is considered broken.
+const MAX_CONSECUTIVE_WATCHER_ERRORS: u64 = 5;
 
+/// State of the watcher thread.
+#[derive(Default)]
+struct WatcherState {
+    events: AtomicU64,
+    errors: AtomicU64,
+    consecutive_errors: AtomicU64,
+    stopped: AtomicBool,
+    /// The key db has been dropped, so the watcher stopping is expected.
+    closed: AtomicBool,
+}
+
+impl WatcherState {
+    fn record(&self, success: bool) {
+        if success {
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+        } else {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            self.consecutive_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Fails if the watcher stopped or keeps failing, so that changes are no longer picked up.
+    fn check(&self) -> Result<()> {
+        if self.stopped.load(Ordering::Relaxed) {
+            bail!("file watcher stopped");
+        }
+        let errors = self.consecutive_errors.load(Ordering::Relaxed);
+        if errors >= MAX_CONSECUTIVE_WATCHER_ERRORS {
+            bail!("file watcher failed {errors} times in a row");
+        }
+        Ok(())
+    }
+}
+
+/// Key store backed by a directory, which is watched for changes.
 pub struct KeyDb {
     _watcher: RecommendedWatcher,
+    _stop_revalidation: Sender<()>,
+    watcher_state: Arc<WatcherState>,
     keys: Arc<RwLock<Cache>>,
+    events: broadcast::Sender<KeyStoreEvent>,
+    key_path: PathBuf,
+    options: KeyOptions,
 }
 
 impl KeyDb {
-    pub async fn new(key_path: &Path, split_keys: bool) -> Result<Self> {
+    pub async fn new(key_path: &Path, options: KeyOptions) -> Result<Self> {
         if !key_path.exists() || !key_path.is_dir() {
             bail!("Key path not found");
         }
+        // file paths are compared against the preferred directory, so they have to be absolute
+        let key_path = key_path.canonicalize()?;
 
-        let cache = Arc::new(RwLock::new(HashMap::new()));
+        let cache = Arc::new(RwLock::new(Cache::new(&key_path, options.clone())));
+        let (events, _) = broadcast::channel(64);
 
         let (tx, rx) = channel();
+        let (wake_tx, wake_rx) = channel();
+
+        let watcher_state = Arc::new(WatcherState::default());
 
         let inner_cache = cache.clone();
+        let inner_events = events.clone();
+        let inner_state = watcher_state.clone();
         let mut watcher = notify::recommended_watcher(tx)?;
 
-        watcher.watch(key_path, notify::RecursiveMode::Recursive)?;
+        watcher.watch(&key_path, notify::RecursiveMode::Recursive)?;
 
-        task::spawn(async move {
+        // receiving blocks, so this must not occupy one of the runtime's worker threads
+        thread::spawn(move || {
             while let Ok(event) = rx.recv() {
                 match event {
                     Ok(event) => {
                         debug!("event: {:?}", event);
-                        if let Err(e) =
-                            Self::handle_file_event(&inner_cache, event, split_keys).await
-                        {
+                        inner_state.events.fetch_add(1, Ordering::Relaxed);
+                        let result = Self::handle_file_event(&inner_cache, &inner_events, event);
+                        if let Err(e) = &result {
                             error!("Error while handling file event: {:?}", e);
                         }
+                        inner_state.record(result.is_ok());
+                        // fails once the scheduler stopped
+                        let _ = wake_tx.send(());
+                    }
+                    Err(error) => {
+                        error!("watch error: {:?}", error);
+                        inner_state.record(false);
                     }
-                    Err(error) => error!("watch error: {:?}", error),
                 }
             }
+            if !inner_state.closed.load(Ordering::Relaxed) {
+                error!("File watcher stopped, changes to the keys path are no longer picked up");
+            }
+            inner_state.stopped.store(true, Ordering::Relaxed);
         });
 
-        let mut db = Self {
-            _watcher: watcher,
-            keys: cache,
-        };
-
-        db.populate(key_path, split_keys).await?;
-
-        Ok(db)
-    }
-
-    async fn populate(&mut self, key_path: &Path, split_keys: bool) -> Result<()> {
-        info!("Populating keys db, key splitting enabled: {split_keys}");
+        // the revalidation thread ends once the key db, holding the sender, is dropped
+        let (stop_tx, stop_rx) = channel::<()>();
+        let inner_cache = cache.clone();
+        let inner_events = events.clone();
+        let interval = options.revalidate_interval;
+        thread::spawn(move || {
+            while stop_rx.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                let changed = inner_cache.write().unwrap().revalidate();
+                notify_changed(&inner_events, changed);
+            }
+        });
 
-        let mut read_dir = fs::read_dir(key_path).await?;
+        cache.write().unwrap().populate()?;
 
-        let mut lock = self.keys.write().await;
+        // reads files again when a certificate in them expires or becomes valid. It ends once the watcher thread,
+        // holding the sender of the wake-up channel, ends.
+        let inner_cache = cache.clone();
+        let inner_events = events.clone();
+        thread::spawn(move || {
+            loop {
+                let next = inner_cache.read().unwrap().next_transition();
+                // file events may add an earlier transition, so they wake the scheduler up
+                let woken = match next {
+                    Some(time) => wake_rx.recv_timeout(
+                        time.duration_since(SystemTime::now())
+                            .unwrap_or(Duration::ZERO),
+                    ),
+                    None => wake_rx.recv().map_err(RecvTimeoutError::from),
+                };
+                if woken == Err(RecvTimeoutError::Disconnected) {
+                    break;
+                }
 
-        while let Some(file) = read_dir.next_entry().await? {
-            if let Err(e) = Self::cache_file(&mut lock, &file.path(), split_keys) {
-                error!("error caching file: {:?}", e);
+                let changed = inner_cache.write().unwrap().read_due_files();
+                notify_changed(&inner_events, changed);
             }
-        }
-
-        info!("Populated db with {} keys", lock.len());
+        });
 
-        Ok(())
+        Ok(Self {
+            _watcher: watcher,
+            _stop_revalidation: stop_tx,
+            watcher_state,
+            keys: cache,
+            events,
+            key_path,
+            options,
+        })
     }
 
-    async fn handle_file_event(
+    fn handle_file_event(
         cache: &RwLock<Cache>,
+        events: &broadcast::Sender<KeyStoreEvent>,
         event: notify::Event,
-        split_keys: bool,
     ) -> Result<()> {
+        let mut lock = cache.write().unwrap();
+        let mut changed = Vec::new();
+
         match event.kind {
-            EventKind::Create(CreateKind::File) => {
-                for path in event.paths {
-                    Self::cache_file(&mut cache.write().await, &path, split_keys)?;
-                }
-            }
-            EventKind::Modify(ModifyKind::Data(_)) => {
+            EventKind::Create(CreateKind::File | CreateKind::Folder)
+            | EventKind::Modify(ModifyKind::Data(_)) => {
                 for path in event.paths {
-                    Self::cache_file(&mut cache.write().await, &path, split_keys)?;
+                    changed.extend(lock.cache_path(&path)?);
                 }
             }
             EventKind::Modify(ModifyKind::Name(_)) => {
-                let mut lock = cache.write().await;
-
                 for path in event.paths {
-                    if let Ok(true) = fs::try_exists(&path).await {
-                        Self::cache_file(&mut lock, &path, split_keys)?;
+                    if path.exists() {
+                        changed.extend(lock.cache_path(&path)?);
                     } else {
-                        Self::remove_file_from_cache(&mut lock, &path)?;
+                        changed.extend(lock.remove_path(&path));
                     }
                 }
             }
-            EventKind::Remove(RemoveKind::File) => {
+            EventKind::Remove(RemoveKind::File | RemoveKind::Folder) => {
                 for path in event.paths {
-                    Self::remove_file_from_cache(&mut cache.write().await, &path)?;
+                    changed.extend(lock.remove_path(&path));
                 }
             }
             _ => { /* ignore */ }
         }
 
+        notify_changed(events, changed);
+
         Ok(())
     }
+}
 
-    fn cache_file(
-        cache: &mut RwLockWriteGuard<Cache>,
-        path: &Path,
-        split_keys: bool,
-    ) -> Result<()> {
-        // first, we remove all files that might be in here still because of this path
-        Self::remove_file_from_cache(cache, path)?;
+impl Drop for KeyDb {
+    fn drop(&mut self) {
+        // runs before the watcher is dropped, which ends the watcher thread
+        self.watcher_state.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl KeyStore for KeyDb {
+    fn get(&self, key: &CertKey, username: Option<&str>) -> Result<Option<Vec<u8>>> {
+        self.keys.read().unwrap().get(key, username)
+    }
+
+    fn fingerprints(&self, key: &CertKey) -> Result<Vec<Fingerprint>> {
+        Ok(self.keys.read().unwrap().fingerprints(key))
+    }
+
+    fn list(&self) -> Result<Vec<StoredKey>> {
+        Ok(self.keys.read().unwrap().list())
+    }
+
+    fn secret_key_files(&self) -> Vec<String> {
+        self.keys
+            .read()
+            .unwrap()
+            .secret_key_files()
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect()
+    }
 
-        let entries = read_key_file(path, split_keys).context("Reading file")?;
-        if entries.is_empty() {
-            info!("Ignoring file {}, no entries found", path.to_string_lossy());
-            return Ok(());
+    fn stats(&self) -> StoreStats {
+        let keys = self.keys.read().unwrap();
+        StoreStats {
+            watcher_events: self.watcher_state.events.load(Ordering::Relaxed),
+            watcher_errors: self.watcher_state.errors.load(Ordering::Relaxed),
+            load_errors: keys.load_errors(),
+            secret_key_files: keys.secret_key_files().len() as u64,
         }
-        entries.into_iter().for_each(|(entry, content)| {
+    }
+
+    /// Also fails while files with secret key material are in the key path, so that the leak gets noticed.
+    fn ready(&self) -> Result<()> {
+        self.watcher_state.check()?;
+        let secret_key_files = self.secret_key_files();
+        if !secret_key_files.is_empty() {
+            bail!(
+                "{} files in the keys path contain secret key material: {}",
+                secret_key_files.len(),
+                secret_key_files.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes `cert` into the key path, the file watcher then adds it to the db.
+    fn publish(&self, cert: &Cert) -> Result<()> {
+        for path in write_key_file(&self.key_path, cert, &self.options)? {
             info!(
-                "Adding key '{}@{}' from file {} to db",
-                content.username,
-                entry.domain,
+                "Wrote key {} to {}",
+                cert.fingerprint(),
                 path.to_string_lossy()
             );
-            cache.insert(entry, content);
-        });
+        }
         Ok(())
     }
 
-    fn remove_file_from_cache(cache: &mut RwLockWriteGuard<Cache>, path: &Path) -> Result<()> {
-        // we remove all items that were inserted into the map because of this file.
-        cache.retain(|_, v| v.path != path.as_os_str());
-        Ok(())
+    /// Removes the certificate from the files it was read from, the file watcher then removes it from the db.
+    fn remove(&self, fingerprint: &Fingerprint) -> Result<Vec<CertKey>> {
+        let stored = self
+            .list()?
+            .into_iter()
+            .filter(|key| &key.fingerprint == fingerprint)
+            .collect::<Vec<_>>();
+
+        let paths = stored
+            .iter()
+            .map(|key| PathBuf::from(&key.source))
+            .collect::<HashSet<_>>();
+        for path in paths {
+            remove_from_key_file(&path, fingerprint)?;
+            info!(
+                "Removed key {} from {}",
+                fingerprint,
+                path.to_string_lossy()
+            );
+        }
+
+        Ok(stored.into_iter().map(|key| key.key).collect())
     }
 
-    pub async fn get(
-        &self,
-        hash: &str,
-        domain: &str,
-        username: Option<&String>,
-    ) -> Result<Option<Vec<u8>>> {
-        let value = self
-            .keys
-            .read()
-            .await
-            .get(&CertKey {
-                hashed_username: hash.to_string(),
-                domain: domain.to_string(),
-            })
-            .cloned();
-
-        match (username, value) {
-            (Some(requested), Some(CertEntry { username, .. })) if requested != &username => {
-                info!(
-                    "hash matched for '{username}@{domain}', but requested local part '{requested}' did not match. Ignoring."
-                );
-                Ok(None)
+    fn subscribe(&self) -> broadcast::Receiver<KeyStoreEvent> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::db::{KeyDb, MAX_CONSECUTIVE_WATCHER_ERRORS, WatcherState};
+    use crate::keys::{KeyOptions, KeyStore};
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::serialize::SerializeInto;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn watcher_state() {
+        let state = WatcherState::default();
+        assert!(state.check().is_ok());
+
+        for _ in 0..MAX_CONSECUTIVE_WATCHER_ERRORS {
+            state.record(false);
+        }
+        assert!(state.check().is_err());
+
+        // a single successful event recovers
+        state.record(true);
+        assert!(state.check().is_ok());
+        assert_eq!(
+            state.errors.load(Ordering::Relaxed),
+            MAX_CONSECUTIVE_WATCHER_ERRORS
+        );
+
+        state.stopped.store(true, Ordering::Relaxed);
+        assert!(state.check().is_err());
+    }
+
+    #[tokio::test]
+    async fn secret_key_files() {
+        let dir = TempDir::new().unwrap();
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let secret = dir.path().join("alice.asc");
+        std::fs::write(&secret, cert.as_tsk().armored().to_vec().unwrap()).unwrap();
+
+        let options = KeyOptions {
+            reject_secret_keys: true,
+            ..KeyOptions::default()
+        };
+        let db = KeyDb::new(dir.path(), options).await.unwrap();
+        assert_eq!(db.stats().secret_key_files, 1);
+        assert!(db.ready().is_err());
+
+        // replacing the file by the public key is picked up by the watcher
+        std::fs::write(&secret, cert.armored().to_vec().unwrap()).unwrap();
+        for _ in 0..50 {
+            if db.ready().is_ok() {
+                break;
             }
-            (_, value) => Ok(value.map(|entry| entry.cert.to_vec().unwrap())),
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        assert!(db.ready().is_ok());
+        assert_eq!(db.stats().secret_key_files, 0);
     }
 }
@@ -1,3 +1,4 @@
+use crate::domain::normalize_domain;
 use crate::keys::db::CertKey;
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -31,7 +32,7 @@ pub fn mail_to_key_entry(email: &str) -> anyhow::Result<Option<(String, CertKey)
 
     let key = CertKey {
         hashed_username,
-        domain: host.to_string(),
+        domain: normalize_domain(host)?,
     };
 
     Ok(Some((username.into(), key)))
@@ -99,6 +100,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn normalized_domain() {
+        let (_, key) = mail_to_key_entry("m@Example.COM.").unwrap().unwrap();
+        assert_eq!(key.domain, "example.com");
+        let (_, key) = mail_to_key_entry("m@bücher.example").unwrap().unwrap();
+        assert_eq!(key.domain, "xn--bcher-kva.example");
+        assert!(mail_to_key_entry("m@exa mple.com").is_err());
+    }
+
     #[test]
     fn file_path_empty() {
         assert!(mail_to_key_entry("/").unwrap().is_none());
@@ -0,0 +1,256 @@
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use sequoia_openpgp::packet::Signature;
+use sequoia_openpgp::serialize::MarshalInto;
+use sequoia_openpgp::{Cert, Packet};
+use std::cmp::Reverse;
+use std::iter;
+use std::path::Path;
+use tracing::warn;
+
+/// What to do with a certificate exceeding the [`CertLimits`].
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FloodAction {
+    /// Drop the excess components and signatures, third-party certifications first.
+    #[default]
+    Trim,
+    /// Do not serve the certificate at all.
+    Reject,
+}
+
+/// Limits protecting against flooded certificates, e.g. certificates polluted with thousands
+/// of third-party signatures.
+#[derive(Clone, Debug)]
+pub struct CertLimits {
+    /// Maximum size of the serialized certificate in bytes.
+    pub max_size: usize,
+    /// Maximum number of signatures on the primary key, a user ID or a subkey.
+    pub max_signatures: usize,
+    /// Maximum number of user IDs, user attributes are limited separately to the same number.
+    pub max_userids: usize,
+    pub max_subkeys: usize,
+    pub action: FloodAction,
+}
+
+impl CertLimits {
+    /// Returns `cert` if it is within the limits, otherwise trims or rejects it.
+    /// `path` is only used for logging.
+    pub fn enforce(&self, cert: Cert, path: &Path) -> Result<Cert> {
+        let violations = self.violations(&cert);
+        if violations.is_empty() {
+            return Ok(cert);
+        }
+        let violations = violations.join(", ");
+
+        if self.action == FloodAction::Reject {
+            bail!("certificate exceeds limits: {violations}");
+        }
+
+        warn!(
+            "Key {} in {} exceeds limits: {}. Trimming it.",
+            cert.fingerprint(),
+            path.to_string_lossy(),
+            violations
+        );
+        let mut trimmed = self.trim(&cert, true)?;
+        if trimmed.serialized_len() > self.max_size {
+            trimmed = self.trim(&cert, false)?;
+        }
+        if trimmed.serialized_len() > self.max_size {
+            bail!(
+                "certificate exceeds the maximum size of {} bytes even without third-party certifications",
+                self.max_size
+            );
+        }
+
+        Ok(trimmed)
+    }
+
+    /// Returns the limits `cert` exceeds, empty if it is within all of them.
+    pub fn violations(&self, cert: &Cert) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        let size = cert.serialized_len();
+        if size > self.max_size {
+            violations.push(format!("{size} bytes"));
+        }
+
+        let userids = cert.userids().count();
+        if userids > self.max_userids {
+            violations.push(format!("{userids} user IDs"));
+        }
+        let user_attributes = cert.user_attributes().count();
+        if user_attributes > self.max_userids {
+            violations.push(format!("{user_attributes} user attributes"));
+        }
+        let subkeys = cert.keys().subkeys().count();
+        if subkeys > self.max_subkeys {
+            violations.push(format!("{subkeys} subkeys"));
+        }
+
+        let signatures = iter::once(cert.primary_key().signatures().count())
+            .chain(cert.userids().map(|userid| userid.signatures().count()))
+            .chain(
+                cert.user_attributes()
+                    .map(|attribute| attribute.signatures().count()),
+            )
+            .chain(
+                cert.keys()
+                    .subkeys()
+                    .map(|subkey| subkey.signatures().count()),
+            )
+            .max()
+            .unwrap_or(0);
+        if signatures > self.max_signatures {
+            violations.push(format!("{signatures} signatures on a single component"));
+        }
+
+        violations
+    }
+
+    /// Rebuilds `cert` from the first user IDs and subkeys, keeping at most `max_signatures` signatures
+    /// per component: self-revocations first, then self-signatures and certifications, newest first.
+    fn trim(&self, cert: &Cert, keep_certifications: bool) -> Result<Cert> {
+        let max = self.max_signatures;
+        let primary = cert.primary_key();
+        let mut packets = vec![Packet::from(primary.key().clone())];
+        packets.extend(limit_signatures(
+            primary.self_revocations().collect(),
+            primary.self_signatures().collect(),
+            primary
+                .other_revocations()
+                .chain(primary.certifications())
+                .collect(),
+            max,
+            keep_certifications,
+        ));
+
+        for userid in cert.userids().take(self.max_userids) {
+            packets.push(userid.userid().clone().into());
+            packets.extend(limit_signatures(
+                userid.self_revocations().collect(),
+                userid.self_signatures().collect(),
+                userid
+                    .other_revocations()
+                    .chain(userid.certifications())
+                    .collect(),
+                max,
+                keep_certifications,
+            ));
+        }
+
+        for attribute in cert.user_attributes().take(self.max_userids) {
+            packets.push(attribute.user_attribute().clone().into());
+            packets.extend(limit_signatures(
+                attribute.self_revocations().collect(),
+                attribute.self_signatures().collect(),
+                attribute
+                    .other_revocations()
+                    .chain(attribute.certifications())
+                    .collect(),
+                max,
+                keep_certifications,
+            ));
+        }
+
+        for subkey in cert.keys().subkeys().take(self.max_subkeys) {
+            packets.push(subkey.key().clone().into());
+            packets.extend(limit_signatures(
+                subkey.self_revocations().collect(),
+                subkey.self_signatures().collect(),
+                subkey
+                    .other_revocations()
+                    .chain(subkey.certifications())
+                    .collect(),
+                max,
+                keep_certifications,
+            ));
+        }
+
+        Cert::from_packets(packets.into_iter())
+    }
+}
+
+fn limit_signatures(
+    revocations: Vec<&Signature>,
+    signatures: Vec<&Signature>,
+    mut certifications: Vec<&Signature>,
+    max: usize,
+    keep_certifications: bool,
+) -> Vec<Packet> {
+    if !keep_certifications {
+        certifications.clear();
+    }
+
+    let mut kept = Vec::new();
+    for mut group in [revocations, signatures, certifications] {
+        group.sort_by_key(|signature| Reverse(signature.signature_creation_time()));
+        let room = max.saturating_sub(kept.len());
+        kept.extend(group.into_iter().take(room));
+    }
+
+    kept.into_iter().cloned().map(Packet::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::limits::{CertLimits, FloodAction};
+    use sequoia_openpgp::Packet;
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::packet::UserID;
+    use sequoia_openpgp::types::SignatureType;
+    use std::path::Path;
+
+    #[test]
+    fn flooded_userid() {
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let userid = UserID::from("alice@example.com");
+
+        let mut packets = vec![Packet::from(userid.clone())];
+        for _ in 0..5 {
+            let (certifier, _) = CertBuilder::new().generate().unwrap();
+            let mut signer = certifier
+                .primary_key()
+                .key()
+                .clone()
+                .parts_into_secret()
+                .unwrap()
+                .into_keypair()
+                .unwrap();
+            let certification = userid
+                .certify(
+                    &mut signer,
+                    &cert,
+                    SignatureType::GenericCertification,
+                    None,
+                    None,
+                )
+                .unwrap();
+            packets.push(certification.into());
+        }
+        let (cert, _) = cert.insert_packets(packets).unwrap();
+
+        let mut limits = CertLimits {
+            max_size: 1 << 16,
+            max_signatures: 3,
+            max_userids: 10,
+            max_subkeys: 10,
+            action: FloodAction::Trim,
+        };
+        let trimmed = limits.enforce(cert.clone(), Path::new("a.asc")).unwrap();
+        let userid = trimmed.userids().next().unwrap();
+        assert_eq!(userid.self_signatures().count(), 1);
+        assert_eq!(userid.certifications().count(), 2);
+
+        limits.action = FloodAction::Reject;
+        assert!(limits.enforce(cert.clone(), Path::new("a.asc")).is_err());
+
+        limits.max_signatures = 6;
+        assert_eq!(
+            limits.enforce(cert.clone(), Path::new("a.asc")).unwrap(),
+            cert
+        );
+    }
+}
@@ -1,5 +1,25 @@
+mod cache;
+mod clean;
+mod conflict;
 mod db;
 mod fs;
 mod hash;
+mod limits;
+mod options;
+mod sqlite;
+mod store;
+mod validation;
 
-pub use db::KeyDb;
+pub use clean::CleanMode;
+pub use conflict::{ConflictResolution, ConflictStrategy, has_conflict};
+pub use db::{CertEntry, CertKey, KeyDb};
+pub use fs::{
+    cert_entries, cert_rejection, is_binary, key_file_path, key_files, parse_cert, parse_certs,
+    parse_key_file, read_keys, retain_address_userids, retain_served_userids,
+};
+pub use hash::mail_to_key_entry;
+pub use limits::{CertLimits, FloodAction};
+pub use options::{KeyLayout, KeyOptions, StoreKind};
+pub use sqlite::{SqliteStore, open_database, remove_stale_sources, replace_source};
+pub use store::{KeyStore, KeyStoreEvent, StoreStats, StoredKey};
+pub use validation::{DigestAlgorithm, KeyAlgorithm, Validation};
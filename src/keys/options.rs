@@ -0,0 +1,52 @@
+use crate::domain::Domains;
+use crate::keys::clean::CleanMode;
+use crate::keys::conflict::ConflictResolution;
+use crate::keys::limits::CertLimits;
+use crate::keys::validation::Validation;
+use clap::ValueEnum;
+use std::time::Duration;
+
+/// How key files are organized in the keys path.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyLayout {
+    /// Files with arbitrary names, addresses are taken from the user IDs.
+    #[default]
+    UserIds,
+    /// The layout used by gpg-wks-server and static WKD trees: `<domain>/hu/<hash>`.
+    Wks,
+}
+
+/// Where the served keys are stored.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    /// Keys are read from the keys path, which is watched for changes, and held in memory.
+    #[default]
+    Directory,
+    /// Keys are looked up in a SQLite database filled by the `import` command.
+    Sqlite,
+}
+
+/// Settings controlling how keys are read from disk and served.
+#[derive(Clone, Debug)]
+pub struct KeyOptions {
+    /// Only include the requested user ID in served certificates.
+    pub split_keys: bool,
+    /// How key files are organized in the keys path.
+    pub layout: KeyLayout,
+    /// Only user IDs of these domains are indexed.
+    pub domains: Domains,
+    /// How many levels of subdirectories of the keys path are read.
+    pub max_depth: usize,
+    /// Refuse files containing secret key material entirely, instead of only serving their public parts.
+    pub reject_secret_keys: bool,
+    /// How certificates are cleaned before they are served.
+    pub clean: CleanMode,
+    /// Limits protecting against flooded certificates.
+    pub limits: CertLimits,
+    /// The policy certificates are validated against.
+    pub validation: Validation,
+    /// How often indexed certificates are checked for expiry and revocation.
+    pub revalidate_interval: Duration,
+    /// How to serve addresses claimed by multiple certificates.
+    pub conflicts: ConflictResolution,
+}
@@ -0,0 +1,533 @@
+use crate::keys::cache::serve_entries;
+use crate::keys::clean::clean_cert;
+use crate::keys::db::{CertEntry, CertKey};
+use crate::keys::fs::{cert_entries, entry_rejection, expiration_time, retain_served_userids};
+use crate::keys::store::{KeyStore, KeyStoreEvent, StoreStats, StoredKey, notify_changed};
+use crate::keys::{KeyOptions, Validation};
+use anyhow::{Context, Result, bail};
+use rusqlite::{Connection, params};
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::serialize::SerializeInto;
+use sequoia_openpgp::{Cert, Fingerprint};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS certs (
+    hashed_username TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    username TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    source TEXT NOT NULL,
+    cert BLOB NOT NULL,
+    -- seconds since the epoch, NULL if the certificate does not expire
+    expiration INTEGER
+);
+CREATE INDEX IF NOT EXISTS certs_address ON certs (domain, hashed_username);
+CREATE INDEX IF NOT EXISTS certs_source ON certs (source);
+";
+
+/// Prefix of the source of keys published through the server.
+const PUBLISHED_SOURCE: &str = "published:";
+
+/// Opens the database at `path`, creating it and its tables if necessary.
+pub fn open_database(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)
+        .with_context(|| format!("Opening database {}", path.to_string_lossy()))?;
+    // lets the server keep reading while an import is running
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.execute_batch(SCHEMA)?;
+    add_expiration(&conn)?;
+    Ok(conn)
+}
+
+/// Adds the expiration column to databases created before it existed, so that listing keys does not have
+/// to parse every certificate.
+fn add_expiration(conn: &Connection) -> Result<()> {
+    if conn.prepare("SELECT expiration FROM certs LIMIT 0").is_ok() {
+        return Ok(());
+    }
+    info!("Adding expiration times to database");
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("ALTER TABLE certs ADD COLUMN expiration INTEGER", [])?;
+    let rows = tx
+        .prepare("SELECT rowid, cert FROM certs")?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let validation = Validation::default();
+    for (rowid, cert) in rows {
+        // unreadable rows are skipped when they are served
+        let Ok(cert) = Cert::from_bytes(&cert) else {
+            continue;
+        };
+        tx.execute(
+            "UPDATE certs SET expiration = ?1 WHERE rowid = ?2",
+            params![expiration_time(&cert, &validation).map(to_timestamp), rowid],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn to_timestamp(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+fn from_timestamp(timestamp: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+}
+
+/// Replaces all rows read from `source` by `entries`.
+pub fn replace_source(
+    conn: &Connection,
+    source: &str,
+    entries: &[(CertKey, CertEntry)],
+) -> Result<()> {
+    conn.execute("DELETE FROM certs WHERE source = ?1", params![source])?;
+
+    let mut insert = conn.prepare_cached(
+        "INSERT INTO certs (hashed_username, domain, username, fingerprint, source, cert, expiration)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for (key, entry) in entries {
+        insert.execute(params![
+            key.hashed_username,
+            key.domain,
+            entry.username,
+            entry.cert.fingerprint().to_hex(),
+            source,
+            entry.cert.to_vec()?,
+            entry.expiration.map(to_timestamp),
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Removes rows whose source lies below `key_path` but is not contained in `seen`,
+/// i.e. rows of files that have been deleted since the last import. Returns the number of removed sources.
+pub fn remove_stale_sources(
+    conn: &Connection,
+    key_path: &Path,
+    seen: &HashSet<String>,
+) -> Result<usize> {
+    let sources = conn
+        .prepare("SELECT DISTINCT source FROM certs")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut removed = 0;
+    for source in sources {
+        if Path::new(&source).starts_with(key_path) && !seen.contains(&source) {
+            conn.execute("DELETE FROM certs WHERE source = ?1", params![source])?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Logs the addresses claimed by more than one certificate. This happens once when the database is opened,
+/// instead of on every request, like the directory store does when indexing.
+fn warn_conflicts(conn: &Connection) -> Result<()> {
+    let mut conflicts = conn.prepare(
+        "SELECT domain, hashed_username, group_concat(DISTINCT fingerprint) FROM certs
+         GROUP BY domain, hashed_username HAVING COUNT(DISTINCT fingerprint) > 1",
+    )?;
+    let mut rows = conflicts.query([])?;
+    while let Some(row) = rows.next()? {
+        warn!(
+            "Conflict: hash {} in domain {} is claimed by keys {}",
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(2)?
+        );
+    }
+    Ok(())
+}
+
+/// Key store backed by a SQLite database, which is filled by the `import` command.
+/// Certificates are only parsed when they are requested.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    events: broadcast::Sender<KeyStoreEvent>,
+    options: KeyOptions,
+}
+
+impl SqliteStore {
+    pub fn new(path: &Path, options: KeyOptions) -> Result<Self> {
+        if !path.is_file() {
+            bail!("Database not found");
+        }
+        let conn = open_database(path)?;
+
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM certs", [], |row| row.get(0))?;
+        info!(
+            "Opened database {} with {} keys",
+            path.to_string_lossy(),
+            count
+        );
+
+        Self::with_connection(conn, options)
+    }
+
+    /// Serves the keys of a database opened with [`open_database`].
+    fn with_connection(conn: Connection, options: KeyOptions) -> Result<Self> {
+        warn_conflicts(&conn)?;
+
+        let (events, _) = broadcast::channel(64);
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            events,
+            options,
+        })
+    }
+}
+
+impl KeyStore for SqliteStore {
+    fn get(&self, key: &CertKey, username: Option<&str>) -> Result<Option<Vec<u8>>> {
+        let entries = {
+            let conn = self.conn.lock().unwrap();
+            let mut query = conn.prepare_cached(
+                "SELECT username, source, cert, expiration FROM certs
+                 WHERE domain = ?1 AND hashed_username = ?2
+                 ORDER BY source, fingerprint",
+            )?;
+            query
+                .query_map(params![key.domain, key.hashed_username], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Vec<u8>>(2)?,
+                        row.get::<_, Option<i64>>(3)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?
+        };
+
+        let mut valid = Vec::new();
+        for (username, source, cert, expiration) in entries {
+            // a corrupt row must not keep the other certificates of the address from being served
+            let cert = match Cert::from_bytes(&cert) {
+                Ok(cert) => cert,
+                Err(e) => {
+                    error!(
+                        "Skipping unreadable key '{}@{}' from {}: {:#}",
+                        username, key.domain, source, e
+                    );
+                    continue;
+                }
+            };
+            let entry = CertEntry {
+                username,
+                cert,
+                path: OsString::from(source),
+                expiration: expiration.map(from_timestamp),
+            };
+            // the database is only updated by imports, so validity is checked on every lookup
+            match entry_rejection(key, &entry, &self.options.validation) {
+                Some(reason) => info!(
+                    "Not serving key '{}@{}' from {}: {}",
+                    entry.username,
+                    key.domain,
+                    entry.path.to_string_lossy(),
+                    reason
+                ),
+                None => valid.push(entry),
+            }
+        }
+        let entries = valid;
+
+        serve_entries(key, entries, username, &self.options.conflicts)
+    }
+
+    fn fingerprints(&self, key: &CertKey) -> Result<Vec<Fingerprint>> {
+        let conn = self.conn.lock().unwrap();
+        let mut query = conn.prepare_cached(
+            "SELECT fingerprint FROM certs WHERE domain = ?1 AND hashed_username = ?2",
+        )?;
+        let fingerprints = query
+            .query_map(params![key.domain, key.hashed_username], |row| {
+                row.get::<_, String>(0)
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        fingerprints
+            .iter()
+            .map(|fingerprint| Fingerprint::from_hex(fingerprint))
+            .collect()
+    }
+
+    fn list(&self) -> Result<Vec<StoredKey>> {
+        let rows = {
+            let conn = self.conn.lock().unwrap();
+            let mut query = conn.prepare(
+                "SELECT hashed_username, domain, username, fingerprint, source, expiration FROM certs
+                 ORDER BY domain, hashed_username, source, fingerprint",
+            )?;
+            query
+                .query_map([], |row| {
+                    Ok((
+                        CertKey {
+                            hashed_username: row.get(0)?,
+                            domain: row.get(1)?,
+                        },
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, String>(4)?,
+                        row.get::<_, Option<i64>>(5)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?
+        };
+
+        rows.into_iter()
+            .map(|(key, username, fingerprint, source, expiration)| {
+                Ok(StoredKey {
+                    key,
+                    username,
+                    fingerprint: Fingerprint::from_hex(&fingerprint)?,
+                    source,
+                    expiration: expiration.map(from_timestamp),
+                })
+            })
+            .collect()
+    }
+
+    /// The import refuses such files, so the database never contains them.
+    fn secret_key_files(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The database is only written by imports, which report their errors themselves.
+    fn stats(&self) -> StoreStats {
+        StoreStats::default()
+    }
+
+    fn ready(&self) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT 1", [], |_| Ok(()))
+            .context("Querying database")
+    }
+
+    /// Stores `cert` in the database, replacing a previously published version of it.
+    fn publish(&self, cert: &Cert) -> Result<()> {
+        let source = format!("{PUBLISHED_SOURCE}{}", cert.fingerprint().to_hex());
+        let entries = cert_entries(
+            retain_served_userids(
+                clean_cert(
+                    self.options
+                        .limits
+                        .enforce(cert.clone(), Path::new(&source))?,
+                    self.options.clean,
+                    &self.options.validation,
+                )?,
+                &self.options,
+            ),
+            Path::new(&source),
+            self.options.split_keys,
+            &self.options.validation,
+        )?;
+
+        {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            replace_source(&tx, &source, &entries)?;
+            tx.commit()?;
+        }
+        info!("Stored key {} in database", cert.fingerprint());
+
+        let changed = entries
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<HashSet<_>>();
+        notify_changed(&self.events, changed);
+
+        Ok(())
+    }
+
+    /// Rows imported from files are imported again by the next import, unless the files are removed as well.
+    fn remove(&self, fingerprint: &Fingerprint) -> Result<Vec<CertKey>> {
+        let keys = {
+            let conn = self.conn.lock().unwrap();
+            let keys = conn
+                .prepare_cached(
+                    "SELECT DISTINCT hashed_username, domain FROM certs WHERE fingerprint = ?1",
+                )?
+                .query_map(params![fingerprint.to_hex()], |row| {
+                    Ok(CertKey {
+                        hashed_username: row.get(0)?,
+                        domain: row.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            conn.execute(
+                "DELETE FROM certs WHERE fingerprint = ?1",
+                params![fingerprint.to_hex()],
+            )?;
+            keys
+        };
+        info!("Removed key {} from database", fingerprint);
+
+        notify_changed(&self.events, keys.iter().cloned());
+
+        Ok(keys)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<KeyStoreEvent> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::sqlite::{
+        SqliteStore, add_expiration, open_database, remove_stale_sources, replace_source,
+        to_timestamp,
+    };
+    use crate::keys::{KeyOptions, KeyStore, cert_entries, mail_to_key_entry, parse_certs};
+    use rusqlite::{Connection, params};
+    use sequoia_openpgp::Cert;
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::serialize::SerializeInto;
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn cert(userid: &str) -> Cert {
+        CertBuilder::general_purpose(Some(userid))
+            .generate()
+            .unwrap()
+            .0
+    }
+
+    fn import(conn: &Connection, source: &str, cert: &Cert) {
+        let options = KeyOptions::default();
+        let entries =
+            cert_entries(cert.clone(), Path::new(source), false, &options.validation).unwrap();
+        replace_source(conn, source, &entries).unwrap();
+    }
+
+    fn sources(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT DISTINCT source FROM certs ORDER BY source")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let conn = open_database(Path::new(":memory:")).unwrap();
+        let (alice, bob) = (cert("alice@example.com"), cert("bob@example.com"));
+        import(&conn, "/keys/alice.asc", &alice);
+        import(&conn, "/keys/bob.asc", &bob);
+        // importing a file again replaces its rows
+        import(&conn, "/keys/bob.asc", &bob);
+        // a corrupt row does not hide the other certificates of the address
+        let (_, key) = mail_to_key_entry("alice@example.com").unwrap().unwrap();
+        conn.execute(
+            "INSERT INTO certs (hashed_username, domain, username, fingerprint, source, cert)
+             VALUES (?1, ?2, 'alice', 'corrupt', '/keys/corrupt.asc', x'00')",
+            params![key.hashed_username, key.domain],
+        )
+        .unwrap();
+
+        let store = SqliteStore::with_connection(conn, KeyOptions::default()).unwrap();
+        let served = parse_certs(&store.get(&key, Some("alice")).unwrap().unwrap());
+        assert_eq!(served.len(), 1);
+        assert_eq!(
+            served[0].as_ref().unwrap().fingerprint(),
+            alice.fingerprint()
+        );
+        assert!(store.get(&key, Some("mallory")).unwrap().is_none());
+
+        let (_, key) = mail_to_key_entry("bob@example.com").unwrap().unwrap();
+        assert_eq!(store.fingerprints(&key).unwrap(), [bob.fingerprint()]);
+    }
+
+    #[test]
+    fn stale_sources() {
+        let conn = open_database(Path::new(":memory:")).unwrap();
+        let alice = cert("alice@example.com");
+        for source in [
+            "/keys/a.asc",
+            "/keys/team/b.asc",
+            "/other/c.asc",
+            "published:ABCD",
+        ] {
+            import(&conn, source, &alice);
+        }
+
+        let seen = HashSet::from(["/keys/a.asc".to_string()]);
+        assert_eq!(
+            remove_stale_sources(&conn, Path::new("/keys"), &seen).unwrap(),
+            1
+        );
+        // rows of other key paths and published keys are kept
+        assert_eq!(
+            sources(&conn),
+            ["/keys/a.asc", "/other/c.asc", "published:ABCD"]
+        );
+    }
+
+    #[test]
+    fn expiration() {
+        let (expiring, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .set_validity_period(Duration::from_secs(24 * 60 * 60))
+            .generate()
+            .unwrap();
+        let expires =
+            expiring.primary_key().key().creation_time() + Duration::from_secs(24 * 60 * 60);
+
+        // a database created before expiration times were stored
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE certs (hashed_username TEXT NOT NULL, domain TEXT NOT NULL,
+             username TEXT NOT NULL, fingerprint TEXT NOT NULL, source TEXT NOT NULL, cert BLOB NOT NULL)",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO certs VALUES ('hash', 'example.com', 'alice', ?1, '/keys/a.asc', ?2)",
+            params![expiring.fingerprint().to_hex(), expiring.to_vec().unwrap()],
+        )
+        .unwrap();
+        add_expiration(&conn).unwrap();
+        let stored: Option<i64> = conn
+            .query_row("SELECT expiration FROM certs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, Some(to_timestamp(expires)));
+
+        // imports store the expiration, which is listed without parsing the certificates
+        let conn = open_database(Path::new(":memory:")).unwrap();
+        import(&conn, "/keys/alice.asc", &expiring);
+        let (unlimited, _) = CertBuilder::general_purpose(Some("bob@example.com"))
+            .set_validity_period(None)
+            .generate()
+            .unwrap();
+        import(&conn, "/keys/bob.asc", &unlimited);
+        let store = SqliteStore::with_connection(conn, KeyOptions::default()).unwrap();
+        let expirations = store
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|key| (key.username, key.expiration.map(to_timestamp)))
+            .collect::<Vec<_>>();
+        assert!(expirations.contains(&("alice".to_string(), Some(to_timestamp(expires)))));
+        assert!(expirations.contains(&("bob".to_string(), None)));
+    }
+}
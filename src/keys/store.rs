@@ -0,0 +1,81 @@
+use crate::keys::db::CertKey;
+use anyhow::Result;
+use sequoia_openpgp::{Cert, Fingerprint};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+/// A certificate stored for an address.
+#[derive(Clone, Debug)]
+pub struct StoredKey {
+    pub key: CertKey,
+    pub username: String,
+    pub fingerprint: Fingerprint,
+    /// Where the certificate was read from, e.g. the file path.
+    pub source: String,
+    /// When the certificate expires, `None` if it does not.
+    pub expiration: Option<SystemTime>,
+}
+
+/// Counters describing how reading the keys went, exported as metrics.
+#[derive(Clone, Debug, Default)]
+pub struct StoreStats {
+    /// File system events handled by the watcher.
+    pub watcher_events: u64,
+    /// Errors reported by the watcher.
+    pub watcher_errors: u64,
+    /// Files and keys that could not be read.
+    pub load_errors: u64,
+    /// Files currently refused because they contain secret key material.
+    pub secret_key_files: u64,
+}
+
+/// The certificates stored for an address changed.
+#[derive(Clone, Debug)]
+pub struct KeyStoreEvent {
+    pub key: CertKey,
+}
+
+/// Notifies the subscribers of a store that the certificates of `keys` changed.
+pub fn notify_changed(
+    events: &broadcast::Sender<KeyStoreEvent>,
+    keys: impl IntoIterator<Item = CertKey>,
+) {
+    for key in keys {
+        // sending only fails if nobody is subscribed
+        let _ = events.send(KeyStoreEvent { key });
+    }
+}
+
+/// A backend the served keys are looked up in.
+pub trait KeyStore: Send + Sync {
+    /// Returns all certificates for the address, serialized and concatenated.
+    /// If `username` is set, only certificates whose user ID has this local part are returned.
+    fn get(&self, key: &CertKey, username: Option<&str>) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the fingerprints of all certificates stored for the address.
+    fn fingerprints(&self, key: &CertKey) -> Result<Vec<Fingerprint>>;
+
+    /// Lists all stored certificates.
+    fn list(&self) -> Result<Vec<StoredKey>>;
+
+    /// Returns the sources refused because they contain secret key material,
+    /// see [`KeyOptions::reject_secret_keys`](crate::keys::KeyOptions::reject_secret_keys).
+    fn secret_key_files(&self) -> Vec<String>;
+
+    /// Returns counters about reading the keys.
+    fn stats(&self) -> StoreStats;
+
+    /// Returns an error describing why keys cannot be served reliably, e.g. because changes are no longer picked up
+    /// or files containing secret key material were found.
+    fn ready(&self) -> Result<()>;
+
+    /// Stores `cert` for all of its addresses.
+    fn publish(&self, cert: &Cert) -> Result<()>;
+
+    /// Removes the certificate with `fingerprint` from all addresses. Returns the addresses it was stored for,
+    /// which are empty if it was not found.
+    fn remove(&self, fingerprint: &Fingerprint) -> Result<Vec<CertKey>>;
+
+    /// Returns a receiver notified whenever the stored certificates change.
+    fn subscribe(&self) -> broadcast::Receiver<KeyStoreEvent>;
+}
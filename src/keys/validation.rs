@@ -0,0 +1,265 @@
+use clap::ValueEnum;
+use sequoia_openpgp::Cert;
+use sequoia_openpgp::cert::amalgamation::ValidateAmalgamation;
+use sequoia_openpgp::policy::{AsymmetricAlgorithm, Policy, StandardPolicy};
+use sequoia_openpgp::types::HashAlgorithm;
+use std::time::SystemTime;
+
+/// Public key algorithms that can be rejected in addition to the defaults of the standard policy.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    /// RSA keys with less than 2048 bits.
+    Rsa1024,
+    /// RSA keys with 2048 to 3071 bits.
+    Rsa2048,
+    /// RSA keys with 3072 to 4095 bits.
+    Rsa3072,
+    /// RSA keys with 4096 bits or more.
+    Rsa4096,
+    Dsa1024,
+    Dsa2048,
+    Dsa3072,
+    Dsa4096,
+    Elgamal1024,
+    Elgamal2048,
+    Elgamal3072,
+    Elgamal4096,
+    NistP256,
+    NistP384,
+    NistP521,
+    BrainpoolP256,
+    BrainpoolP384,
+    BrainpoolP512,
+    Cv25519,
+    X25519,
+    X448,
+    Ed25519,
+    Ed448,
+    /// Ed25519 keys in the legacy v4 format.
+    Eddsa,
+}
+
+impl From<KeyAlgorithm> for AsymmetricAlgorithm {
+    fn from(algorithm: KeyAlgorithm) -> Self {
+        match algorithm {
+            KeyAlgorithm::Rsa1024 => AsymmetricAlgorithm::RSA1024,
+            KeyAlgorithm::Rsa2048 => AsymmetricAlgorithm::RSA2048,
+            KeyAlgorithm::Rsa3072 => AsymmetricAlgorithm::RSA3072,
+            KeyAlgorithm::Rsa4096 => AsymmetricAlgorithm::RSA4096,
+            KeyAlgorithm::Dsa1024 => AsymmetricAlgorithm::DSA1024,
+            KeyAlgorithm::Dsa2048 => AsymmetricAlgorithm::DSA2048,
+            KeyAlgorithm::Dsa3072 => AsymmetricAlgorithm::DSA3072,
+            KeyAlgorithm::Dsa4096 => AsymmetricAlgorithm::DSA4096,
+            KeyAlgorithm::Elgamal1024 => AsymmetricAlgorithm::ElGamal1024,
+            KeyAlgorithm::Elgamal2048 => AsymmetricAlgorithm::ElGamal2048,
+            KeyAlgorithm::Elgamal3072 => AsymmetricAlgorithm::ElGamal3072,
+            KeyAlgorithm::Elgamal4096 => AsymmetricAlgorithm::ElGamal4096,
+            KeyAlgorithm::NistP256 => AsymmetricAlgorithm::NistP256,
+            KeyAlgorithm::NistP384 => AsymmetricAlgorithm::NistP384,
+            KeyAlgorithm::NistP521 => AsymmetricAlgorithm::NistP521,
+            KeyAlgorithm::BrainpoolP256 => AsymmetricAlgorithm::BrainpoolP256,
+            KeyAlgorithm::BrainpoolP384 => AsymmetricAlgorithm::BrainpoolP384,
+            KeyAlgorithm::BrainpoolP512 => AsymmetricAlgorithm::BrainpoolP512,
+            KeyAlgorithm::Cv25519 => AsymmetricAlgorithm::Cv25519,
+            KeyAlgorithm::X25519 => AsymmetricAlgorithm::X25519,
+            KeyAlgorithm::X448 => AsymmetricAlgorithm::X448,
+            KeyAlgorithm::Ed25519 => AsymmetricAlgorithm::Ed25519,
+            KeyAlgorithm::Ed448 => AsymmetricAlgorithm::Ed448,
+            KeyAlgorithm::Eddsa => AsymmetricAlgorithm::EdDSA,
+        }
+    }
+}
+
+/// Hash algorithms that can be rejected in addition to the defaults of the standard policy.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Md5,
+    Sha1,
+    Ripemd160,
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl From<DigestAlgorithm> for HashAlgorithm {
+    fn from(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Md5 => HashAlgorithm::MD5,
+            DigestAlgorithm::Sha1 => HashAlgorithm::SHA1,
+            DigestAlgorithm::Ripemd160 => HashAlgorithm::RipeMD,
+            DigestAlgorithm::Sha224 => HashAlgorithm::SHA224,
+            DigestAlgorithm::Sha256 => HashAlgorithm::SHA256,
+            DigestAlgorithm::Sha384 => HashAlgorithm::SHA384,
+            DigestAlgorithm::Sha512 => HashAlgorithm::SHA512,
+        }
+    }
+}
+
+/// The OpenPGP policy and the reference time certificates are validated against.
+#[derive(Clone, Debug)]
+pub struct Validation {
+    policy: StandardPolicy<'static>,
+    time: Option<SystemTime>,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Self {
+            policy: StandardPolicy::new(),
+            time: None,
+        }
+    }
+}
+
+impl Validation {
+    /// Extends the standard policy by rejecting the given algorithms in all contexts.
+    /// Without a reference time, certificates are validated at the current time.
+    pub fn new(
+        reject_algorithms: &[KeyAlgorithm],
+        reject_hashes: &[DigestAlgorithm],
+        time: Option<SystemTime>,
+    ) -> Self {
+        let mut policy = StandardPolicy::new();
+        for &algorithm in reject_algorithms {
+            policy.reject_asymmetric_algo(algorithm.into());
+        }
+        for &algorithm in reject_hashes {
+            // this includes binding signatures, for which e.g. SHA-1 is still accepted by default
+            policy.reject_hash(algorithm.into());
+        }
+
+        Self { policy, time }
+    }
+
+    pub fn policy(&self) -> &dyn Policy {
+        &self.policy
+    }
+
+    /// The time certificates are validated at, `None` for the current time.
+    pub fn time(&self) -> Option<SystemTime> {
+        self.time
+    }
+
+    /// Returns the subkeys and user IDs of `cert` that are rejected by the policy, and why.
+    /// Whether the certificate as a whole is valid is checked when it is read, if it is not,
+    /// nothing is returned here.
+    pub fn rejections(&self, cert: &Cert) -> Vec<String> {
+        let mut rejections = Vec::new();
+        if cert.with_policy(self.policy(), self.time).is_err() {
+            return rejections;
+        }
+
+        for subkey in cert.keys().subkeys() {
+            if let Err(e) = subkey.with_policy(self.policy(), self.time) {
+                rejections.push(format!(
+                    "subkey {} rejected: {:#}",
+                    subkey.key().fingerprint(),
+                    e
+                ));
+            }
+        }
+        for userid in cert.userids() {
+            if let Err(e) = userid.with_policy(self.policy(), self.time) {
+                rejections.push(format!("user ID {} rejected: {:#}", userid.userid(), e));
+            }
+        }
+
+        rejections
+    }
+
+    /// Returns the next time after now at which the validity of `cert` or one of its components may change,
+    /// because a key, a user ID binding or a revocation is created or expires at that time.
+    /// There are no transitions when validating at a fixed reference time.
+    pub fn next_transition(&self, cert: &Cert) -> Option<SystemTime> {
+        if self.time.is_some() {
+            return None;
+        }
+
+        let mut times = Vec::new();
+        for key in cert.keys() {
+            times.push(key.key().creation_time());
+            for signature in key.self_signatures().chain(key.self_revocations()) {
+                times.extend(signature.signature_creation_time());
+                times.extend(signature.signature_expiration_time());
+                times.extend(signature.key_expiration_time(key.key()));
+            }
+        }
+        for userid in cert.userids() {
+            for signature in userid.self_signatures().chain(userid.self_revocations()) {
+                times.extend(signature.signature_creation_time());
+                times.extend(signature.signature_expiration_time());
+                // binding signatures of the primary user ID may carry the expiration of the certificate
+                times.extend(signature.key_expiration_time(cert.primary_key().key()));
+            }
+        }
+
+        let now = SystemTime::now();
+        times.into_iter().filter(|&time| time > now).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::validation::{KeyAlgorithm, Validation};
+    use sequoia_openpgp::cert::CertBuilder;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn reject_algorithm() {
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+
+        assert!(Validation::default().rejections(&cert).is_empty());
+
+        // the encryption subkey is rejected, the certificate itself is still valid
+        let validation = Validation::new(&[KeyAlgorithm::Cv25519], &[], None);
+        assert!(
+            cert.with_policy(validation.policy(), validation.time())
+                .is_ok()
+        );
+        let rejections = validation.rejections(&cert);
+        assert_eq!(rejections.len(), 1);
+        assert!(rejections[0].starts_with("subkey"));
+    }
+
+    #[test]
+    fn next_transition() {
+        let now = SystemTime::now();
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .set_creation_time(now - Duration::from_secs(3600))
+            .set_validity_period(Duration::from_secs(7200))
+            .generate()
+            .unwrap();
+        let expiry = Validation::default().next_transition(&cert).unwrap();
+        assert!(expiry > now && expiry <= now + Duration::from_secs(3600));
+
+        // not valid yet, so the transition is its creation
+        let creation = now + Duration::from_secs(600);
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .set_creation_time(creation)
+            .generate()
+            .unwrap();
+        let validation = Validation::default();
+        assert!(
+            cert.with_policy(validation.policy(), validation.time())
+                .is_err()
+        );
+        assert_eq!(
+            validation
+                .next_transition(&cert)
+                .unwrap()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            creation
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        );
+
+        let validation = Validation::new(&[], &[], Some(now));
+        assert_eq!(validation.next_transition(&cert), None);
+    }
+}
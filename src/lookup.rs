@@ -0,0 +1,209 @@
+use crate::config::{HashConfig, LookupConfig};
+use crate::keys::{CertKey, Validation, is_binary, mail_to_key_entry, parse_certs};
+use anyhow::{Context, Result, bail};
+use reqwest::header::HOST;
+use reqwest::{Client, Url};
+use std::time::Duration;
+
+/// One of the two ways a WKD client looks up keys.
+struct Method {
+    name: &'static str,
+    /// The host a client sends its requests to.
+    host: String,
+    /// The directory containing the policy and the `hu` directory.
+    path: String,
+}
+
+impl Method {
+    /// The advanced method, which clients try first, and the direct method.
+    fn all(domain: &str) -> [Method; 2] {
+        [
+            Method {
+                name: "advanced",
+                host: format!("openpgpkey.{domain}"),
+                path: format!("/.well-known/openpgpkey/{domain}"),
+            },
+            Method {
+                name: "direct",
+                host: domain.to_string(),
+                path: "/.well-known/openpgpkey".to_string(),
+            },
+        ]
+    }
+
+    /// The URL of the host a client sends its requests to.
+    fn host_url(&self) -> Result<Url> {
+        Url::parse(&format!("https://{}", self.host)).context("Invalid domain")
+    }
+
+    fn policy_url(&self, base: &Url) -> Result<Url> {
+        Ok(base.join(&format!("{}/policy", self.path))?)
+    }
+
+    fn key_url(&self, base: &Url, username: &str, key: &CertKey) -> Result<Url> {
+        let mut url = base.join(&format!("{}/hu/{}", self.path, key.hashed_username))?;
+        url.query_pairs_mut().append_pair("l", username);
+        Ok(url)
+    }
+}
+
+fn parse_address(email: &str) -> Result<(String, CertKey)> {
+    mail_to_key_entry(email)?.with_context(|| format!("'{email}' is not an email address"))
+}
+
+/// Prints the hash of the local part of the address, followed by the URLs of both methods.
+pub fn hash(config: &HashConfig) -> Result<()> {
+    let (username, key) = parse_address(&config.email)?;
+
+    println!("{}", key.hashed_username);
+    for method in Method::all(&key.domain) {
+        println!(
+            "{}: {}",
+            method.name,
+            method.key_url(&method.host_url()?, &username, &key)?
+        );
+    }
+
+    Ok(())
+}
+
+/// Fetches the policy and the key of the address with both methods and prints the result of every step.
+/// Fails if neither method serves a key with a user ID for the address.
+pub async fn lookup(config: &LookupConfig) -> Result<()> {
+    let (username, key) = parse_address(&config.email)?;
+    let server = config
+        .server
+        .as_deref()
+        .map(Url::parse)
+        .transpose()
+        .context("Invalid server URL")?;
+    let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+
+    let mut found = false;
+    for method in Method::all(&key.domain) {
+        println!("{} method ({})", method.name, method.host);
+        let base = match &server {
+            Some(server) => server.clone(),
+            None => method.host_url()?,
+        };
+
+        let url = method.policy_url(&base)?;
+        match fetch(&client, &url, server.is_some().then_some(&method.host)).await {
+            Ok(_) => println!("  policy {url}: ok"),
+            Err(e) => println!("  policy {url}: error: {e:#}"),
+        }
+
+        let url = method.key_url(&base, &username, &key)?;
+        match fetch(&client, &url, server.is_some().then_some(&method.host)).await {
+            Ok(content) => {
+                println!("  key {url}: ok, {} bytes", content.len());
+                found |= check_key(&content, &username, &key);
+            }
+            Err(e) => println!("  key {url}: error: {e:#}"),
+        }
+    }
+
+    if !found {
+        bail!("No key found for {}", config.email);
+    }
+
+    Ok(())
+}
+
+/// Fetches `url`, sending `host` as `Host` header if set. Fails on any status but success.
+async fn fetch(client: &Client, url: &Url, host: Option<&String>) -> Result<Vec<u8>> {
+    let mut request = client.get(url.clone());
+    if let Some(host) = host {
+        request = request.header(HOST, host);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("HTTP status {status}");
+    }
+
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Prints the keys of a response and returns whether one of them has a user ID for the address.
+fn check_key(content: &[u8], username: &str, key: &CertKey) -> bool {
+    if !content.is_empty() && !is_binary(content) {
+        println!("    warning: the key is not in binary format, which clients may not accept");
+    }
+
+    let validation = Validation::default();
+    let mut found = false;
+    for cert in parse_certs(content) {
+        let cert = match cert {
+            Ok(cert) => cert,
+            Err(e) => {
+                println!("    error: unreadable key: {e:#}");
+                continue;
+            }
+        };
+
+        let userids = cert
+            .userids()
+            .filter(|userid| {
+                let address = userid
+                    .userid()
+                    .email()
+                    .ok()
+                    .flatten()
+                    .and_then(|email| mail_to_key_entry(email).ok().flatten());
+                address.is_some_and(|(other_username, other)| {
+                    other_username == username && &other == key
+                })
+            })
+            .map(|userid| userid.userid().to_string())
+            .collect::<Vec<_>>();
+        if userids.is_empty() {
+            println!(
+                "    error: key {} has no user ID for this address",
+                cert.fingerprint()
+            );
+        } else {
+            println!("    key {}: {}", cert.fingerprint(), userids.join(", "));
+            found = true;
+        }
+
+        if let Err(e) = cert.with_policy(validation.policy(), validation.time()) {
+            println!(
+                "    warning: key {} is not valid: {e:#}",
+                cert.fingerprint()
+            );
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lookup::{Method, parse_address};
+    use reqwest::Url;
+
+    #[test]
+    fn urls() {
+        let (username, key) = parse_address("first+tag@example.com").unwrap();
+        let [advanced, direct] = Method::all(&key.domain);
+
+        assert_eq!(
+            advanced
+                .key_url(&advanced.host_url().unwrap(), &username, &key)
+                .unwrap()
+                .as_str(),
+            format!(
+                "https://openpgpkey.example.com/.well-known/openpgpkey/example.com/hu/{}?l=first%2Btag",
+                key.hashed_username
+            )
+        );
+
+        let server = Url::parse("http://localhost:8080").unwrap();
+        assert_eq!(
+            direct.policy_url(&server).unwrap().as_str(),
+            "http://localhost:8080/.well-known/openpgpkey/policy"
+        );
+    }
+}
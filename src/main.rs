@@ -1,11 +1,16 @@
-use crate::config::Config;
+use crate::config::{Cli, Command};
 use anyhow::Context;
-use clap::Parser;
 
+mod check;
 mod config;
+mod domain;
+mod export;
 mod http;
+mod import;
 mod keys;
+mod lookup;
 mod policy;
+mod submission;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -13,10 +18,38 @@ async fn main() -> anyhow::Result<()> {
 
     env_logger::init();
 
-    let config = Config::parse();
-    config.validate().context("Failed to validate config")?;
+    match Cli::parse() {
+        Cli::Serve(config) => {
+            config.validate().context("Failed to validate config")?;
 
-    http::serve(config).await?;
+            http::serve(config).await?;
+        }
+        Cli::Command(Command::Export(config)) => {
+            config
+                .keys
+                .validate()
+                .context("Failed to validate config")?;
+
+            export::export(&config).await?;
+        }
+        Cli::Command(Command::Import(config)) => {
+            import::import(&config)?;
+        }
+        Cli::Command(Command::Check(config)) => {
+            config
+                .keys
+                .validate()
+                .context("Failed to validate config")?;
+
+            check::check(&config)?;
+        }
+        Cli::Command(Command::Hash(config)) => {
+            lookup::hash(&config)?;
+        }
+        Cli::Command(Command::Lookup(config)) => {
+            lookup::lookup(&config).await?;
+        }
+    }
 
     Ok(())
 }
@@ -1,29 +1,59 @@
+use crate::domain::is_safe_file_name;
 use std::{fs, io};
 use tracing::error;
 
+/// Subdirectory of the policy directory that contains the submission addresses.
+const SUBMISSION_ADDRESS_DIR: &str = "submission-address";
+
+const EMPTY_POLICY: &str = "# Empty policy\n";
+
+/// Returns the policy served for `domain`. If no policy directory is configured, an empty policy is served.
+pub fn policy_for_domain(policy_dir: Option<&str>, domain: &str) -> Result<String, io::Error> {
+    match policy_dir {
+        None => Ok(EMPTY_POLICY.into()),
+        Some(path) => Ok(get_policy(path, domain)?.unwrap_or_default()),
+    }
+}
+
 pub fn get_policy(policy_dir: &str, domain: &str) -> Result<Option<String>, io::Error> {
     if !fs::exists(policy_dir)? {
         error!("policy dir {} does not exist", policy_dir);
         return Ok(None);
     }
 
-    // first, we check if the domain policy exists.
-    let path = format!("{policy_dir}/{domain}");
-    let policy = try_read_policy(&path)?;
-    if policy.is_some() {
-        return Ok(policy);
+    read_domain_file(policy_dir, domain)
+}
+
+pub fn get_submission_address(policy_dir: &str, domain: &str) -> Result<Option<String>, io::Error> {
+    let dir = format!("{policy_dir}/{SUBMISSION_ADDRESS_DIR}");
+    if !fs::exists(&dir)? {
+        return Ok(None);
+    }
+
+    read_domain_file(&dir, domain)
+}
+
+fn read_domain_file(dir: &str, domain: &str) -> Result<Option<String>, io::Error> {
+    // first, we check if the domain file exists. The domain comes from the request, so it must not
+    // be able to escape the directory.
+    if is_safe_file_name(domain) {
+        let path = format!("{dir}/{domain}");
+        let content = try_read_file(&path)?;
+        if content.is_some() {
+            return Ok(content);
+        }
     }
 
-    // otherwise, we try to serve the default policy.
-    let path = format!("{policy_dir}/default");
-    let policy = try_read_policy(&path)?;
+    // otherwise, we try to serve the default file.
+    let path = format!("{dir}/default");
+    let content = try_read_file(&path)?;
 
-    Ok(policy)
+    Ok(content)
 }
 
-fn try_read_policy(domain_policy: &str) -> Result<Option<String>, io::Error> {
-    let result = if fs::exists(domain_policy)? {
-        Some(fs::read_to_string(domain_policy)?)
+fn try_read_file(path: &str) -> Result<Option<String>, io::Error> {
+    let result = if fs::exists(path)? {
+        Some(fs::read_to_string(path)?)
     } else {
         None
     };
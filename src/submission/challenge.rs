@@ -0,0 +1,39 @@
+use crate::keys::Validation;
+use anyhow::{Result, bail};
+use sequoia_openpgp::Cert;
+use sequoia_openpgp::serialize::stream::{Armorer, Encryptor, LiteralWriter, Message};
+use std::io::Write;
+
+/// Generates a random, URL-safe challenge token.
+pub fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 20];
+    sequoia_openpgp::crypto::random(&mut bytes)?;
+    Ok(zbase32::encode_full_bytes(&bytes))
+}
+
+/// Encrypts `token` to all encryption-capable keys of `cert` that are valid under the configured policy and
+/// returns the armored message.
+pub fn encrypt_challenge(cert: &Cert, token: &str, validation: &Validation) -> Result<String> {
+    let recipients = cert
+        .keys()
+        .with_policy(validation.policy(), validation.time())
+        .supported()
+        .alive()
+        .revoked(false)
+        .for_transport_encryption()
+        .for_storage_encryption()
+        .collect::<Vec<_>>();
+    if recipients.is_empty() {
+        bail!("certificate does not have a valid encryption key");
+    }
+
+    let mut sink = Vec::new();
+    let message = Message::new(&mut sink);
+    let message = Armorer::new(message).build()?;
+    let message = Encryptor::for_recipients(message, recipients).build()?;
+    let mut writer = LiteralWriter::new(message).build()?;
+    writer.write_all(token.as_bytes())?;
+    writer.finalize()?;
+
+    Ok(String::from_utf8(sink)?)
+}
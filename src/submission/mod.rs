@@ -0,0 +1,6 @@
+mod challenge;
+mod mail;
+mod pending;
+
+pub use mail::Mailer;
+pub use pending::*;
@@ -0,0 +1,300 @@
+use crate::keys::Validation;
+use crate::submission::challenge::{encrypt_challenge, random_token};
+use anyhow::Result;
+use sequoia_openpgp::Cert;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a submission waits for its challenge to be confirmed.
+const SUBMISSION_TTL: Duration = Duration::from_secs(60 * 60);
+/// Upper bound on unconfirmed submissions kept in memory. When it is reached, new submissions are rejected
+/// until pending ones are confirmed or expire, so that a flood cannot drop legitimate submissions.
+const MAX_PENDING: usize = 1000;
+/// Upper bound on unconfirmed submissions per address, which also limits the challenges mailed to an address.
+const MAX_PENDING_PER_ADDRESS: usize = 3;
+
+/// A submission was rejected because too many submissions are pending.
+#[derive(Debug)]
+pub struct LimitExceeded(String);
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for LimitExceeded {}
+
+/// A certificate submitted for an address, reduced to the user IDs of that address.
+pub struct Submission {
+    pub address: String,
+    pub cert: Cert,
+}
+
+struct PendingSubmission {
+    submission: Submission,
+    created: Instant,
+}
+
+/// Certificates that were submitted, but whose challenge has not been confirmed yet.
+#[derive(Default)]
+pub struct Submissions {
+    pending: Mutex<HashMap<String, PendingSubmission>>,
+}
+
+impl Submissions {
+    /// Stores `submission` as pending and returns a challenge encrypted to its certificate, which has to be sent
+    /// to the submitted address.
+    ///
+    /// The decrypted challenge has to be passed to [`Submissions::confirm`] to release the certificate.
+    /// Fails with [`LimitExceeded`] if too many submissions are pending, in total or for the address.
+    pub fn submit(&self, submission: Submission, validation: &Validation) -> Result<String> {
+        let token = random_token()?;
+        let challenge = encrypt_challenge(&submission.cert, &token, validation)?;
+        self.insert(token, submission, Instant::now())?;
+
+        Ok(challenge)
+    }
+
+    fn insert(&self, token: String, submission: Submission, now: Instant) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, pending| now.duration_since(pending.created) < SUBMISSION_TTL);
+        if pending.len() >= MAX_PENDING {
+            return Err(
+                LimitExceeded("Too many pending submissions, try again later.".into()).into(),
+            );
+        }
+        let for_address = pending
+            .values()
+            .filter(|pending| pending.submission.address == submission.address)
+            .count();
+        if for_address >= MAX_PENDING_PER_ADDRESS {
+            return Err(LimitExceeded(format!(
+                "Too many pending submissions for {}, confirm one of them or try again later.",
+                submission.address
+            ))
+            .into());
+        }
+
+        pending.insert(
+            token,
+            PendingSubmission {
+                submission,
+                created: now,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes and returns the submission belonging to the decrypted challenge `token`.
+    pub fn confirm(&self, token: &str) -> Option<Submission> {
+        self.confirm_at(token, Instant::now())
+    }
+
+    fn confirm_at(&self, token: &str, now: Instant) -> Option<Submission> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|pending| now.duration_since(pending.created) < SUBMISSION_TTL)
+            .map(|pending| pending.submission)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::{KeyAlgorithm, Validation};
+    use crate::submission::pending::{
+        LimitExceeded, MAX_PENDING, MAX_PENDING_PER_ADDRESS, SUBMISSION_TTL, Submission,
+        Submissions,
+    };
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::crypto::SessionKey;
+    use sequoia_openpgp::packet::{PKESK, SKESK};
+    use sequoia_openpgp::parse::Parse;
+    use sequoia_openpgp::parse::stream::{
+        DecryptionHelper, DecryptorBuilder, MessageStructure, VerificationHelper,
+    };
+    use sequoia_openpgp::policy::StandardPolicy;
+    use sequoia_openpgp::types::SymmetricAlgorithm;
+    use sequoia_openpgp::{Cert, KeyHandle};
+    use std::io::Read;
+    use std::time::{Duration, Instant};
+
+    struct Helper(Cert);
+
+    impl VerificationHelper for Helper {
+        fn get_certs(&mut self, _: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+            Ok(Vec::new())
+        }
+
+        fn check(&mut self, _: MessageStructure) -> sequoia_openpgp::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl DecryptionHelper for Helper {
+        fn decrypt(
+            &mut self,
+            pkesks: &[PKESK],
+            _: &[SKESK],
+            sym_algo: Option<SymmetricAlgorithm>,
+            decrypt: &mut dyn FnMut(Option<SymmetricAlgorithm>, &SessionKey) -> bool,
+        ) -> sequoia_openpgp::Result<Option<Cert>> {
+            let policy = StandardPolicy::new();
+            for key in self
+                .0
+                .keys()
+                .with_policy(&policy, None)
+                .for_transport_encryption()
+                .secret()
+            {
+                let mut keypair = key.key().clone().into_keypair()?;
+                for pkesk in pkesks {
+                    if let Some((algo, session_key)) = pkesk.decrypt(&mut keypair, sym_algo)
+                        && decrypt(algo, &session_key)
+                    {
+                        return Ok(None);
+                    }
+                }
+            }
+            Ok(None)
+        }
+    }
+
+    fn submission(cert: &Cert) -> Submission {
+        submission_for("alice@example.com", cert)
+    }
+
+    fn submission_for(address: &str, cert: &Cert) -> Submission {
+        Submission {
+            address: address.to_string(),
+            cert: cert.clone().strip_secret_key_material(),
+        }
+    }
+
+    fn decrypt(tsk: &Cert, challenge: &str) -> String {
+        let policy = StandardPolicy::new();
+        let mut decryptor = DecryptorBuilder::from_bytes(challenge.as_bytes())
+            .unwrap()
+            .with_policy(&policy, None, Helper(tsk.clone()))
+            .unwrap();
+        let mut token = String::new();
+        decryptor.read_to_string(&mut token).unwrap();
+        token
+    }
+
+    #[test]
+    fn confirm() {
+        let (tsk, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let submissions = Submissions::default();
+        let validation = Validation::default();
+
+        let challenge = submissions.submit(submission(&tsk), &validation).unwrap();
+        let token = decrypt(&tsk, &challenge);
+
+        assert!(submissions.confirm("wrong token").is_none());
+        let confirmed = submissions.confirm(&token).unwrap();
+        assert_eq!(confirmed.address, "alice@example.com");
+        assert_eq!(confirmed.cert.fingerprint(), tsk.fingerprint());
+        // a token can only be used once
+        assert!(submissions.confirm(&token).is_none());
+
+        // the challenge is only encrypted to keys accepted by the configured policy
+        let validation = Validation::new(&[KeyAlgorithm::Cv25519], &[], None);
+        assert!(submissions.submit(submission(&tsk), &validation).is_err());
+    }
+
+    #[test]
+    fn expiry() {
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let submissions = Submissions::default();
+        let now = Instant::now();
+
+        submissions
+            .insert("a".to_string(), submission(&cert), now)
+            .unwrap();
+        submissions
+            .insert("b".to_string(), submission(&cert), now)
+            .unwrap();
+        assert!(
+            submissions
+                .confirm_at("a", now + SUBMISSION_TTL - Duration::from_secs(1))
+                .is_some()
+        );
+        assert!(submissions.confirm_at("b", now + SUBMISSION_TTL).is_none());
+    }
+
+    #[test]
+    fn full() {
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let submissions = Submissions::default();
+        let now = Instant::now();
+
+        for i in 0..MAX_PENDING {
+            let address = format!("user{i}@example.com");
+            submissions
+                .insert(i.to_string(), submission_for(&address, &cert), now)
+                .unwrap();
+        }
+        // new submissions are rejected instead of dropping pending ones
+        let error = submissions
+            .insert("new".to_string(), submission(&cert), now)
+            .unwrap_err();
+        assert!(error.downcast_ref::<LimitExceeded>().is_some());
+        let later = now + Duration::from_secs(1);
+        assert!(submissions.confirm_at("0", later).is_some());
+
+        // confirming or expiring makes room again
+        submissions
+            .insert("new".to_string(), submission(&cert), later)
+            .unwrap();
+        assert!(
+            submissions
+                .insert("newer".to_string(), submission(&cert), now + SUBMISSION_TTL)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn per_address() {
+        let (cert, _) = CertBuilder::general_purpose(Some("alice@example.com"))
+            .generate()
+            .unwrap();
+        let submissions = Submissions::default();
+        let now = Instant::now();
+
+        for i in 0..MAX_PENDING_PER_ADDRESS {
+            submissions
+                .insert(i.to_string(), submission(&cert), now)
+                .unwrap();
+        }
+        let error = submissions
+            .insert("new".to_string(), submission(&cert), now)
+            .unwrap_err();
+        assert!(error.downcast_ref::<LimitExceeded>().is_some());
+
+        // other addresses are not affected
+        submissions
+            .insert(
+                "bob".to_string(),
+                submission_for("bob@example.com", &cert),
+                now,
+            )
+            .unwrap();
+        // confirming a submission makes room for the address
+        assert!(submissions.confirm_at("0", now).is_some());
+        submissions
+            .insert("new".to_string(), submission(&cert), now)
+            .unwrap();
+    }
+}